@@ -73,8 +73,8 @@ fn activate_macos_app() {}
 use std::collections::VecDeque;
 use std::fs;
 use std::io::{BufRead, BufReader};
-use std::net::{SocketAddr, TcpStream};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 use std::sync::{mpsc, Arc};
 use std::time::{Duration, Instant};
@@ -165,7 +165,7 @@ fn default_config_path() -> PathBuf {
 }
 
 fn example_config_text() -> &'static str {
-    include_str!("../../cortex-proxy.example.toml")
+    include_str!("../../cortex-proxy-rs/cortex-proxy.example.toml")
 }
 
 /// Try to find bundled example config in app Resources folder (macOS)
@@ -251,6 +251,13 @@ struct AppState {
     status: String,
     last_started: Option<Instant>,
     child: Option<Child>,
+    /// Set while waiting for the proxy to finish in-flight requests
+    stop_requested: Option<Instant>,
+    /// Result of the `cortex-proxy check` run that gates a start: the effective
+    /// `shutdown_timeout_secs` on success
+    checking: Option<mpsc::Receiver<Result<Option<u64>, Vec<String>>>>,
+    /// `shutdown_timeout_secs` of the running proxy, after env overrides and `${VAR}`s
+    shutdown_timeout_secs: Option<u64>,
     log_rx: Option<mpsc::Receiver<String>>,
     logs: VecDeque<String>,
     show_window: bool,
@@ -288,6 +295,9 @@ impl AppState {
             status: "Stopped".to_string(),
             last_started: None,
             child: None,
+            stop_requested: None,
+            checking: None,
+            shutdown_timeout_secs: None,
             log_rx: None,
             logs: VecDeque::with_capacity(300),
             show_window: true,
@@ -336,16 +346,23 @@ impl AppState {
     }
 
     fn start_proxy(&mut self) {
+        if self.stop_requested.is_some() {
+            self.append_log("Proxy is still stopping; try again in a moment.".to_string());
+            return;
+        }
         if self.child.is_some() {
             self.append_log("Proxy already managed by this GUI.".to_string());
             return;
         }
+        if self.checking.is_some() {
+            self.append_log("Config check already running.".to_string());
+            return;
+        }
         
         // Check if already running externally
-        let port = self.proxy_port();
-        if check_proxy_health(port) {
-            self.status = format!("Running (external) :{}", port);
-            self.append_log(format!("Proxy already running on port {}.", port));
+        if let Some(addr) = self.health_addr().filter(|addr| check_proxy_health(*addr)) {
+            self.status = format!("Running (external) {}", addr);
+            self.append_log(format!("Proxy already running on {}.", addr));
             return;
         }
         
@@ -377,11 +394,50 @@ impl AppState {
             return;
         }
 
-        // Start the proxy
-        self.append_log(format!("Starting: {} --config {}", self.proxy_bin, self.config_path.display()));
+        // Run the proxy's own validation so mistakes show up with file:line.
+        // It runs on a thread so the UI keeps drawing; `poll_child` starts the
+        // proxy once it passes.
+        let (tx, rx) = mpsc::channel();
+        let proxy_bin = self.proxy_bin.clone();
+        let config_path = self.config_path.clone();
+        std::thread::spawn(move || {
+            let result = check_config(&proxy_bin, &config_path)
+                .map(|()| effective_shutdown_timeout(&proxy_bin, &config_path));
+            let _ = tx.send(result);
+        });
+        self.checking = Some(rx);
+        self.status = "Checking config...".to_string();
+    }
+
+    /// Handles a finished config check: spawns the proxy or logs the report
+    fn poll_check(&mut self) {
+        let Some(rx) = &self.checking else { return };
+        let result = match rx.try_recv() {
+            Ok(result) => result,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => Err(vec!["Config check ended without a result".to_string()]),
+        };
+        self.checking = None;
+        match result {
+            Ok(shutdown_timeout_secs) => {
+                self.shutdown_timeout_secs = shutdown_timeout_secs;
+                self.spawn_proxy();
+            }
+            Err(report) => {
+                self.append_log("Config check failed; fix the config and click Start again:".to_string());
+                for line in report {
+                    self.append_log(line);
+                }
+            }
+        }
+    }
+
+    fn spawn_proxy(&mut self) {
+        // Closing the proxy's stdin asks it to shut down gracefully
+        self.append_log(format!("Starting: {} --config {} serve --shutdown-on-eof", self.proxy_bin, self.config_path.display()));
         let mut cmd = Command::new(&self.proxy_bin);
-        cmd.arg("--config").arg(&self.config_path);
-        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        cmd.arg("--config").arg(&self.config_path).arg("serve").arg("--shutdown-on-eof");
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
         match cmd.spawn() {
             Ok(mut child) => {
                 self.status = "Starting...".to_string();
@@ -413,18 +469,59 @@ impl AppState {
         }
     }
 
+    /// Asks the proxy to finish in-flight requests and exit; `poll_child` kills it
+    /// if it is still running after `stop_timeout`
     fn stop_proxy(&mut self) {
-        if let Some(mut child) = self.child.take() {
+        let Some(child) = &mut self.child else { return };
+        if self.stop_requested.is_some() {
+            return;
+        }
+        drop(child.stdin.take());
+        self.stop_requested = Some(Instant::now());
+        self.status = "Stopping...".to_string();
+        self.append_log("Stopping proxy; waiting for in-flight requests to finish...".to_string());
+    }
+
+    /// The proxy's `shutdown_timeout_secs` plus time to flush and exit
+    fn stop_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs.unwrap_or(30) + 5)
+    }
+
+    /// Kills the proxy if it has outlived its stop request
+    fn kill_overdue_proxy(&mut self) {
+        let overdue = self.stop_requested.is_some_and(|t| t.elapsed() > self.stop_timeout());
+        if let (true, Some(mut child)) = (overdue, self.child.take()) {
             let _ = child.kill();
             let _ = child.wait();
+            self.stop_requested = None;
+            self.last_started = None;
+            self.append_log("Proxy did not stop in time; killed it.".to_string());
         }
-        self.status = "Stopped".to_string();
-        self.last_started = None;
-        self.append_log("Proxy stopped.".to_string());
+    }
+
+    /// Stops the proxy before the GUI exits. Waiting for it (or killing it once
+    /// `stop_timeout` passes) happens on a thread, so the UI never blocks;
+    /// `main` joins that thread after the event loop has ended.
+    fn stop_proxy_in_background(&mut self) -> Option<std::thread::JoinHandle<()>> {
+        self.stop_proxy();
+        let mut child = self.child.take()?;
+        let deadline = self.stop_requested.take().unwrap_or_else(Instant::now) + self.stop_timeout();
+        Some(std::thread::spawn(move || {
+            while matches!(child.try_wait(), Ok(None)) {
+                if Instant::now() > deadline {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }))
     }
 
     fn poll_child(&mut self) {
-        // Collect logs first
+        self.poll_check();
+
+        // Collect logs
         if let Some(rx) = &self.log_rx {
             let mut pending = Vec::new();
             while let Ok(line) = rx.try_recv() {
@@ -438,23 +535,44 @@ impl AppState {
         // Check if our child process exited
         if let Some(child) = &mut self.child {
             if let Ok(Some(status)) = child.try_wait() {
-                self.append_log(format!("Proxy exited: {}", status));
+                match self.stop_requested.take() {
+                    Some(_) => self.append_log("Proxy stopped.".to_string()),
+                    None => self.append_log(format!("Proxy exited: {}", status)),
+                }
                 self.last_started = None;
                 self.child = None;
             }
         }
+        self.kill_overdue_proxy();
 
         // Update status based on actual port state
         self.refresh_status();
     }
 
     fn refresh_status(&mut self) {
-        let port = self.proxy_port();
-        let healthy = check_proxy_health(port);
+        if self.stop_requested.is_some() {
+            self.status = "Stopping...".to_string();
+            return;
+        }
+        if self.checking.is_some() {
+            self.status = "Checking config...".to_string();
+            return;
+        }
+        let Some(addr) = self.health_addr() else {
+            // TLS or a Unix socket: nothing to probe, so go by the child process
+            if self.child.is_some() {
+                self.status = "Running (no health check)".to_string();
+            } else {
+                self.status = "Stopped".to_string();
+                self.last_started = None;
+            }
+            return;
+        };
+        let healthy = check_proxy_health(addr);
 
         if self.child.is_some() && healthy {
             // GUI started the proxy and it's responding
-            self.status = format!("Running :{}", port);
+            self.status = format!("Running {}", addr);
         } else if self.child.is_some() && !healthy {
             // GUI started it but it's not responding yet (starting up or crashed)
             if let Some(started) = self.last_started {
@@ -466,7 +584,7 @@ impl AppState {
             }
         } else if healthy {
             // Healthy but not our child - external process
-            self.status = format!("Running (external) :{}", port);
+            self.status = format!("Running (external) {}", addr);
         } else {
             self.status = "Stopped".to_string();
             self.last_started = None;
@@ -474,7 +592,10 @@ impl AppState {
     }
 
     fn is_running(&self) -> bool {
-        check_proxy_health(self.proxy_port())
+        match self.health_addr() {
+            Some(addr) => check_proxy_health(addr),
+            None => self.child.is_some(),
+        }
     }
 
     fn toggle_proxy(&mut self) {
@@ -499,8 +620,8 @@ impl AppState {
         }
     }
 
-    fn proxy_port(&self) -> u16 {
-        parse_port_from_toml(&self.config_text).unwrap_or(8766)
+    fn health_addr(&self) -> Option<SocketAddr> {
+        health_addr_from_toml(&self.config_text)
     }
 }
 
@@ -629,6 +750,8 @@ struct GuiApp {
     tray_icon: Option<TrayIcon>,
     menu_ids: MenuIds,
     state: AppState,
+    /// Waits for the proxy to stop after the GUI quits
+    stopper: Option<std::thread::JoinHandle<()>>,
 }
 
 #[derive(Default, Clone)]
@@ -651,6 +774,7 @@ impl GuiApp {
             tray_icon: None,
             menu_ids: MenuIds::default(),
             state: AppState::new(),
+            stopper: None,
         }
     }
 
@@ -811,10 +935,13 @@ impl winit::application::ApplicationHandler<UserEvent> for GuiApp {
     }
 
     fn exiting(&mut self, _event_loop: &winit::event_loop::ActiveEventLoop) {
+        if let Some(win) = self.gl_window.as_ref().map(|w| w.window()) {
+            win.set_visible(false);
+        }
         if let Some(egui_glow) = &mut self.egui_glow {
             egui_glow.destroy();
         }
-        self.state.stop_proxy();
+        self.stopper = self.state.stop_proxy_in_background();
     }
 }
 
@@ -829,6 +956,10 @@ impl GuiApp {
                     ui.separator();
 
                     let is_running = self.state.is_running();
+                    if self.state.checking.is_some() {
+                        // Keep polling until the config check reports back
+                        ctx.request_repaint_after(Duration::from_millis(100));
+                    }
                     ui.label(format!("Status: {}", self.state.status));
                     if let Some(started) = self.state.last_started {
                         if self.state.child.is_some() {
@@ -933,14 +1064,77 @@ fn parse_port_from_toml(text: &str) -> Option<u16> {
         .and_then(|p| u16::try_from(p).ok())
 }
 
+/// Where to probe `/health`, following `[proxy] listen` and `port`. None for TLS
+/// and Unix sockets, which the plain-HTTP probe can't reach.
+fn health_addr_from_toml(text: &str) -> Option<SocketAddr> {
+    let val: toml::Value = toml::from_str(text).unwrap_or_else(|_| toml::Value::Table(Default::default()));
+    let proxy = val.get("proxy");
+    if proxy.and_then(|p| p.get("tls")).is_some() {
+        return None;
+    }
+    let listen = proxy.and_then(|p| p.get("listen")).and_then(|l| l.as_str()).unwrap_or("127.0.0.1").trim();
+    if listen.starts_with("unix:") {
+        return None;
+    }
+    let addr = listen.parse::<SocketAddr>().ok().or_else(|| {
+        let host = listen.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(listen);
+        let ip = match host {
+            "localhost" => IpAddr::from([127, 0, 0, 1]),
+            _ => host.parse().ok()?,
+        };
+        Some(SocketAddr::new(ip, parse_port_from_toml(text).unwrap_or(8766)))
+    })?;
+    // A wildcard listen accepts loopback connections of the same family
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::from([127, 0, 0, 1]),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::from(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    Some(SocketAddr::new(ip, addr.port()))
+}
+
+/// `shutdown_timeout_secs` as the proxy resolves it (`cortex-proxy print-config`),
+/// so `${VAR}`s and `CORTEX_PROXY_PROXY__SHUTDOWN_TIMEOUT_SECS` count
+fn effective_shutdown_timeout(proxy_bin: &str, config_path: &Path) -> Option<u64> {
+    let output = Command::new(proxy_bin)
+        .arg("--config")
+        .arg(config_path)
+        .arg("print-config")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let val: toml::Value = toml::from_str(&String::from_utf8_lossy(&output.stdout)).ok()?;
+    val.get("proxy")?
+        .get("shutdown_timeout_secs")?
+        .as_integer()
+        .and_then(|t| u64::try_from(t).ok())
+}
+
 fn is_port_open(port: u16) -> bool {
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
     TcpStream::connect_timeout(&addr, Duration::from_millis(200)).is_ok()
 }
 
+/// Runs `cortex-proxy check` on the config file; Err holds its report
+fn check_config(proxy_bin: &str, config_path: &Path) -> Result<(), Vec<String>> {
+    let output = Command::new(proxy_bin)
+        .arg("--config")
+        .arg(config_path)
+        .arg("check")
+        .output();
+    match output {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(stdout.lines().chain(stderr.lines()).map(str::to_string).collect())
+        }
+        Err(e) => Err(vec![format!("Failed to run config check: {e}")]),
+    }
+}
+
 /// Check if the proxy is responding via /health endpoint
-fn check_proxy_health(port: u16) -> bool {
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+fn check_proxy_health(addr: SocketAddr) -> bool {
     if let Ok(mut stream) = TcpStream::connect_timeout(&addr, Duration::from_millis(500)) {
         use std::io::{Read, Write};
         let _ = stream.set_read_timeout(Some(Duration::from_millis(500)));
@@ -979,4 +1173,10 @@ fn main() {
     let proxy = event_loop.create_proxy();
     let mut app = GuiApp::new(proxy);
     event_loop.run_app(&mut app).expect("failed to run app");
+    // Window and tray icon go first; the proxy may still be finishing requests
+    let stopper = app.stopper.take();
+    drop(app);
+    if let Some(stopper) = stopper {
+        let _ = stopper.join();
+    }
 }
@@ -0,0 +1,117 @@
+//! Inbound client authentication with proxy-issued API keys
+//!
+//! Keys are only stored as SHA-256 hashes. Anthropic clients send them in
+//! `x-api-key`, OpenAI clients as `Authorization: Bearer <key>`; either header
+//! is accepted on every route.
+
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// `[auth]` section
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+}
+
+/// `[[auth.keys]]` entry
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    /// Client identity used in logs, metrics and the usage ledger
+    pub name: String,
+    /// Hex SHA-256 of the key, as printed by `cortex-proxy keygen`
+    pub sha256: String,
+    /// Grants access to `/admin/*`
+    #[serde(default)]
+    pub admin: bool,
+}
+
+pub enum AuthError {
+    Missing,
+    Invalid,
+}
+
+/// Key prefix, so leaked keys are easy to recognise and scan for
+const KEY_PREFIX: &str = "cpk_";
+
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Random 192-bit key
+pub fn generate_key() -> String {
+    let bytes: [u8; 24] = rand::random();
+    format!("{}{}", KEY_PREFIX, bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+/// Compares without short-circuiting on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Key from `x-api-key` or `Authorization: Bearer`
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(key.trim());
+    }
+    let auth = headers.get("authorization")?.to_str().ok()?;
+    let (scheme, token) = auth.trim().split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+impl AuthConfig {
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<&ApiKey, AuthError> {
+        let key = presented_key(headers).filter(|k| !k.is_empty()).ok_or(AuthError::Missing)?;
+        let hash = hash_key(key);
+        // Check every entry so timing doesn't reveal which one matched
+        let mut found = None;
+        for entry in &self.keys {
+            if constant_time_eq(hash.as_bytes(), entry.sha256.trim().to_ascii_lowercase().as_bytes()) {
+                found = Some(entry);
+            }
+        }
+        found.ok_or(AuthError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn config(key: &str) -> AuthConfig {
+        AuthConfig {
+            enabled: true,
+            keys: vec![ApiKey { name: "team-a".to_string(), sha256: hash_key(key), admin: false }],
+        }
+    }
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn accepts_x_api_key_and_bearer() {
+        let key = generate_key();
+        let auth = config(&key);
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(auth.authenticate(&headers("x-api-key", &key)).ok().map(|k| k.name.as_str()), Some("team-a"));
+        let bearer = headers("authorization", &format!("Bearer {}", key));
+        assert_eq!(auth.authenticate(&bearer).ok().map(|k| k.name.as_str()), Some("team-a"));
+    }
+
+    #[test]
+    fn rejects_missing_and_unknown_keys() {
+        let auth = config("cpk_right");
+        assert!(matches!(auth.authenticate(&HeaderMap::new()), Err(AuthError::Missing)));
+        assert!(matches!(auth.authenticate(&headers("x-api-key", "cpk_wrong")), Err(AuthError::Invalid)));
+        assert!(matches!(auth.authenticate(&headers("authorization", "Basic cpk_right")), Err(AuthError::Missing)));
+    }
+}
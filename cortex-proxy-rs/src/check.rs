@@ -0,0 +1,170 @@
+//! Semantic config checks behind `cortex-proxy check`
+//!
+//! Loading already rejects malformed TOML, wrong types and unresolvable secrets.
+//! These checks catch configs that load but won't work as intended (a token sent
+//! over plain http, a model Cortex doesn't know, port 0) and report every problem
+//! at once. `base_url` checks are shared with loading (`validate::check_base_url`).
+
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::LazyLock;
+
+use crate::listener::{self, ListenAddr};
+use crate::models::ModelMapper;
+use crate::routing::Upstreams;
+use crate::{logging, upstream_configs, validate, Config};
+
+/// Shape of a Cortex model id
+static MODEL_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-z0-9][a-z0-9._-]*$").unwrap());
+
+pub struct Finding {
+    pub error: bool,
+    /// Config key the finding is about, e.g. `upstreams[1].base_url`
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mark = if self.error { "✗" } else { "⚠" };
+        write!(f, "{} {}: {}", mark, self.key, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn error(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Finding { error: true, key: key.into(), message: message.into() });
+    }
+
+    fn warn(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Finding { error: false, key: key.into(), message: message.into() });
+    }
+}
+
+pub fn check(config: &Config) -> Vec<Finding> {
+    let mut findings = Findings::default();
+    check_proxy(config, &mut findings);
+    check_upstreams(config, &mut findings);
+    check_models(config, &mut findings);
+    findings.0
+}
+
+fn check_proxy(config: &Config, findings: &mut Findings) {
+    let proxy = &config.proxy;
+    match listener::parse_listen(&proxy.listen, proxy.port) {
+        Err(e) => findings.error("proxy.listen", e),
+        Ok(ListenAddr::Tcp(addr)) => {
+            if addr.port() == 0 {
+                findings.error("proxy.port", "port 0 would bind a random port clients can't find");
+            } else if addr.port() < 1024 {
+                findings.warn("proxy.port", format!("port {} needs root or CAP_NET_BIND_SERVICE", addr.port()));
+            }
+            if !addr.ip().is_loopback() && !config.auth.enabled {
+                findings.warn("proxy.listen", format!("{} is reachable from the network but [auth] is disabled", addr.ip()));
+            }
+        }
+        Ok(ListenAddr::Unix(_)) => {}
+    }
+    if let Some(tls) = &proxy.tls {
+        if let Err(e) = listener::tls_acceptor(tls) {
+            findings.error("proxy.tls", e);
+        }
+    }
+    if let Err(e) = logging::validate_level(&proxy.log_level, proxy.log_bodies) {
+        findings.error("proxy.log_level", e);
+    }
+    if !matches!(proxy.log_format.as_str(), "text" | "json") {
+        findings.error("proxy.log_format", format!("'{}' is not \"text\" or \"json\"", proxy.log_format));
+    }
+    if proxy.timeout_secs == 0 {
+        findings.error("proxy.timeout_secs", "0 makes every request time out immediately");
+    }
+}
+
+fn check_upstreams(config: &Config, findings: &mut Findings) {
+    let configs = upstream_configs(config);
+    for (i, upstream) in configs.iter().enumerate() {
+        let key = match (i, config.snowflake.base_url.is_some()) {
+            (0, true) => "snowflake.base_url".to_string(),
+            (i, true) => format!("upstreams[{}].base_url", i - 1),
+            (i, false) => format!("upstreams[{}].base_url", i),
+        };
+        match validate::check_base_url(&upstream.base_url) {
+            Ok(warnings) => warnings.into_iter().for_each(|w| findings.warn(&key, w)),
+            Err(e) => findings.error(&key, e),
+        }
+    }
+    // Credentials (key files, passphrases), names and routes
+    if let Err(e) = Upstreams::new(&configs, &config.routes, config.upstream_health.clone()) {
+        findings.error("upstreams", e);
+    }
+}
+
+fn check_models(config: &Config, findings: &mut Findings) {
+    if let Err(e) = ModelMapper::new(HashMap::new(), &config.model_rules, &config.model_catalog, None, false) {
+        findings.error("model_rules", e);
+    }
+    let mut check_name = |key: String, model: &str| {
+        if !MODEL_ID.is_match(model) {
+            findings.error(key, format!("'{}' is not a Cortex model id (lowercase letters, digits, '.', '-', '_')", model));
+        } else if !config.model_catalog.iter().any(|m| m.id == model) {
+            findings.warn(key, format!("'{}' is not in model_catalog; check it against the Cortex model list", model));
+        }
+    };
+    check_name("snowflake.default_model".to_string(), &config.snowflake.default_model);
+    if let Some(fallback) = &config.snowflake.fallback_model {
+        check_name("snowflake.fallback_model".to_string(), fallback);
+    }
+    let mut aliases: Vec<_> = config.model_map.iter().collect();
+    aliases.sort();
+    for (alias, target) in aliases {
+        check_name(format!("model_map.{}", alias), target);
+    }
+    for (i, rule) in config.model_rules.iter().enumerate() {
+        // Regex rules may build the target from capture groups
+        if !rule.target.contains('$') {
+            check_name(format!("model_rules[{}].target", i), &rule.target);
+        }
+    }
+    let mut fallbacks: Vec<_> = config.model_fallbacks.iter().collect();
+    fallbacks.sort();
+    for (model, chain) in fallbacks {
+        check_name(format!("model_fallbacks.{}", model), model);
+        for fallback in chain {
+            check_name(format!("model_fallbacks.{}", model), fallback);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn findings(text: &str) -> Vec<String> {
+        let config: Config = toml::from_str(text).unwrap();
+        check(&config).iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn reports_urls_ports_and_models() {
+        let found = findings(
+            "[proxy]\nport = 0\n\
+             [snowflake]\nbase_url = \"ftp://acct.snowflakecomputing.com/api/v2/cortex/v1\"\npat = \"x\"\ndefault_model = \"Claude Sonnet\"\n\
+             [model_map]\nfast = \"claude-haiku-9\"\n\
+             [[upstreams]]\nname = \"eu\"\nbase_url = \"http://eu.example.com/api/v2/cortex/v1\"\npat = \"y\"",
+        );
+        assert_eq!(found, [
+            "✗ proxy.port: port 0 would bind a random port clients can't find",
+            "✗ snowflake.base_url: scheme 'ftp' is not http or https",
+            "⚠ upstreams[0].base_url: plain http sends the Snowflake token unencrypted",
+            "⚠ upstreams[0].base_url: 'eu.example.com' is not a snowflakecomputing.com host",
+            "✗ snowflake.default_model: 'Claude Sonnet' is not a Cortex model id (lowercase letters, digits, '.', '-', '_')",
+            "⚠ model_map.fast: 'claude-haiku-9' is not in model_catalog; check it against the Cortex model list",
+        ]);
+        assert!(findings("[proxy]\n[snowflake]\nbase_url = \"https://acct.snowflakecomputing.com/api/v2/cortex/v1\"\npat = \"x\"").is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+//! Command line: argument parsing and the commands besides `serve`, `usage` and `keygen`
+
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config_env::SECRET_FIELDS;
+use crate::routing::{HealthConfig, Upstream, Upstreams};
+use crate::{check, default_config_paths, find_config_path, read_config, upstream_configs, Config};
+
+#[derive(Parser)]
+#[command(name = "cortex-proxy", version, about = "Anthropic and OpenAI compatible proxy for Snowflake Cortex")]
+pub struct Cli {
+    /// Config file [default: ~/.config/cortex-proxy/config.toml, then ./cortex-proxy.toml]
+    #[arg(long, global = true, env = "CORTEX_PROXY_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the proxy (the default)
+    Serve {
+        /// Shut down gracefully when stdin closes (for a supervising process such as the GUI)
+        #[arg(long)]
+        shutdown_on_eof: bool,
+    },
+    /// Write a new config file, asking for the account, PAT, model and port
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Load the config and report problems without starting the proxy
+    Check,
+    /// Send a minimal request through every upstream and report auth, region and model availability
+    TestConnection {
+        /// Cortex model to try; repeatable [default: snowflake.default_model]
+        #[arg(long = "model", value_name = "MODEL")]
+        models: Vec<String>,
+    },
+    /// Print the effective config (file, environment overrides and defaults) with secrets redacted
+    PrintConfig,
+    /// Summarize the usage ledger
+    Usage {
+        #[arg(long, value_name = "YYYY-MM-DD")]
+        since: Option<String>,
+        #[arg(long, value_name = "YYYY-MM-DD")]
+        until: Option<String>,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create a client API key for [auth]
+    Keygen {
+        #[arg(default_value = "my-client")]
+        name: String,
+    },
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
+
+// ============ init ============
+
+const EXAMPLE_CONFIG: &str = include_str!("../cortex-proxy.example.toml");
+
+/// Asks on stderr and reads one line; end of input or an empty line gives `default`
+fn prompt(question: &str, default: &str) -> String {
+    match default {
+        "" => eprint!("{}: ", question),
+        _ => eprint!("{} [{}]: ", question, default),
+    }
+    let _ = std::io::stderr().flush();
+    let mut line = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut line);
+    match line.trim() {
+        "" => default.to_string(),
+        answer => answer.to_string(),
+    }
+}
+
+/// Replaces the first uncommented `key = ...` line of the example config
+fn set_key(text: &str, key: &str, value: &str) -> String {
+    let prefix = format!("{} =", key);
+    let mut done = false;
+    text.lines()
+        .map(|line| match !done && line.starts_with(&prefix) {
+            true => {
+                done = true;
+                format!("{} = {}", key, value)
+            }
+            false => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n") + "\n"
+}
+
+fn quoted(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+/// `cortex-proxy init [--force]`: writes the example config with the answers filled in
+pub fn init_command(explicit: Option<&Path>, force: bool) {
+    let path = explicit.map(Path::to_path_buf)
+        .or_else(|| default_config_paths().into_iter().next())
+        .unwrap_or_else(|| fail("no config directory; pass --config"));
+    if path.exists() && !force {
+        fail(format!("{} already exists (use --force to overwrite)", path.display()));
+    }
+    eprintln!("Creating {}\n", path.display());
+
+    let account = prompt("Snowflake account identifier (e.g. myorg-myaccount) or Cortex API URL", "");
+    let base_url = match account.as_str() {
+        "" => fail("an account is required"),
+        url if url.contains("://") => url.trim_end_matches('/').to_string(),
+        account => format!("https://{}.snowflakecomputing.com/api/v2/cortex/v1", account.to_lowercase()),
+    };
+    let pat = prompt("Programmatic access token (empty: read $SNOWFLAKE_PAT at startup)", "");
+    let pat = match pat.as_str() {
+        "" => "${SNOWFLAKE_PAT}".to_string(),
+        pat => pat.to_string(),
+    };
+    let model = prompt("Default model", "claude-4-sonnet");
+    let port = loop {
+        match prompt("Port", "8766").parse::<u16>() {
+            Ok(port) if port > 0 => break port,
+            _ => eprintln!("Enter a port between 1 and 65535"),
+        }
+    };
+
+    let mut text = set_key(EXAMPLE_CONFIG, "base_url", &quoted(&base_url));
+    text = set_key(&text, "pat", &quoted(&pat));
+    text = set_key(&text, "default_model", &quoted(&model));
+    text = set_key(&text, "port", &port.to_string());
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| fail(format!("{}: {}", dir.display(), e)));
+    }
+    std::fs::write(&path, text).unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)));
+    // The file may hold the PAT
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600));
+    }
+    eprintln!("\nWrote {}", path.display());
+    eprintln!("Next: `cortex-proxy check`, then `cortex-proxy test-connection`");
+}
+
+// ============ check ============
+
+/// `cortex-proxy check`: exits with 1 when there are errors (warnings alone pass)
+pub fn check_command(explicit: Option<&Path>) {
+    let path = find_config_path(explicit).unwrap_or_else(|e| fail(e));
+    println!("{}", path.display());
+    // Load errors already start with file:line
+    let config = read_config(&path).unwrap_or_else(|e| {
+        for line in e.lines() {
+            println!("  ✗ {}", line);
+        }
+        println!("✗ Config rejected");
+        std::process::exit(1);
+    });
+    let findings = check::check(&config);
+    for finding in &findings {
+        println!("  {}", finding);
+    }
+    let errors = findings.iter().filter(|f| f.error).count();
+    let warnings = findings.len() - errors;
+    match (errors, warnings) {
+        (0, 0) => println!("✓ Config OK"),
+        (0, w) => println!("✓ Config OK with {} warning(s)", w),
+        (e, w) => {
+            println!("✗ {} error(s), {} warning(s)", e, w);
+            std::process::exit(1);
+        }
+    }
+}
+
+// ============ test-connection ============
+
+/// Message from a Cortex/Snowflake error body, or the start of the body
+fn error_message(body: &str) -> String {
+    let parsed: Option<Value> = serde_json::from_str(body).ok();
+    let message = parsed.as_ref().and_then(|v| {
+        v.pointer("/error/message").or_else(|| v.get("message")).and_then(|m| m.as_str())
+    });
+    let message = message.unwrap_or(body).trim();
+    match message.char_indices().nth(200) {
+        Some((i, _)) => format!("{}…", &message[..i]),
+        None => message.to_string(),
+    }
+}
+
+/// Runs `SELECT CURRENT_REGION()` through the SQL API of the upstream's account
+async fn region(client: &reqwest::Client, upstream: &Upstream, auth: &(String, &str)) -> Result<String, (u16, String)> {
+    let url = reqwest::Url::parse(&upstream.base_url).map_err(|e| (0, e.to_string()))?;
+    let resp = client.post(format!("{}/api/v2/statements", url.origin().ascii_serialization()))
+        .header("Authorization", &auth.0)
+        .header("X-Snowflake-Authorization-Token-Type", auth.1)
+        .json(&json!({"statement": "SELECT CURRENT_REGION()", "timeout": 30}))
+        .send()
+        .await
+        .map_err(|e| (0, e.to_string()))?;
+    let status = resp.status().as_u16();
+    let body = resp.text().await.unwrap_or_default();
+    if !(200..300).contains(&status) {
+        return Err((status, error_message(&body)));
+    }
+    serde_json::from_str::<Value>(&body).ok()
+        .and_then(|v| v.pointer("/data/0/0").and_then(|r| r.as_str()).map(str::to_string))
+        .ok_or((status, "no region in the response".to_string()))
+}
+
+/// One-token completion; Ok(latency) when the model answered
+async fn try_model(client: &reqwest::Client, upstream: &Upstream, auth: &(String, &str), model: &str) -> Result<Duration, (u16, String)> {
+    let start = Instant::now();
+    let resp = client.post(format!("{}/chat/completions", upstream.base_url))
+        .header("Authorization", &auth.0)
+        .header("X-Snowflake-Authorization-Token-Type", auth.1)
+        .json(&json!({
+            "model": model,
+            "messages": [{"role": "user", "content": "Reply with OK"}],
+            "max_completion_tokens": 1,
+        }))
+        .send()
+        .await
+        .map_err(|e| (0, e.to_string()))?;
+    let status = resp.status().as_u16();
+    match status {
+        200..=299 => Ok(start.elapsed()),
+        _ => Err((status, error_message(&resp.text().await.unwrap_or_default()))),
+    }
+}
+
+/// `cortex-proxy test-connection [--model M]...`: exits with 1 when any check fails
+pub async fn test_connection_command(config: &Config, models: &[String]) {
+    let upstreams = Upstreams::new(&upstream_configs(config), &[], HealthConfig::default()).unwrap_or_else(|e| fail(e));
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.proxy.timeout_secs.min(60)))
+        .build()
+        .unwrap_or_else(|e| fail(e));
+    let models = match models {
+        [] => vec![config.snowflake.default_model.clone()],
+        models => models.to_vec(),
+    };
+    let mut failed = false;
+    for index in 0..upstreams.len() {
+        let upstream = upstreams.get(index);
+        println!("{} ({})", upstream.name, upstream.base_url);
+        let auth = match upstream.credentials.authorization().await {
+            Ok(auth) => auth,
+            Err(e) => {
+                println!("  ✗ credentials: {}", e);
+                failed = true;
+                continue;
+            }
+        };
+        // The region comes from the SQL API, which a role may be refused while
+        // Cortex accepts it, so only the model requests below decide on auth
+        match region(&client, upstream, &auth).await {
+            Ok(region) => println!("  ✓ region: {}", region),
+            Err((0, message)) => {
+                println!("  ✗ connection: {}", message);
+                failed = true;
+                continue;
+            }
+            Err((status, message)) => println!("  ⚠ region: unknown (SQL API returned {}): {}", status, message),
+        }
+        for model in &models {
+            match try_model(&client, upstream, &auth, model).await {
+                Ok(latency) => println!("  ✓ {}: available ({} ms, {} accepted)", model, latency.as_millis(), auth.1),
+                Err((429, _)) => println!("  ⚠ {}: throttled (429), try again later", model),
+                Err((status, message)) => {
+                    let what = match status {
+                        0 => "request failed",
+                        401 => "token rejected",
+                        403 => "not permitted",
+                        400 | 404 => "not available",
+                        _ => "error",
+                    };
+                    println!("  ✗ {}: {} ({}): {}", model, what, status, message);
+                    failed = true;
+                }
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+// ============ print-config ============
+
+const REDACTED: &str = "<redacted>";
+
+fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                match key.as_str() {
+                    key if SECRET_FIELDS.contains(&key) => *item = toml::Value::String(REDACTED.to_string()),
+                    _ => redact(item),
+                }
+            }
+        }
+        toml::Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// `cortex-proxy print-config`: the config the proxy would run with, as TOML
+pub fn print_config_command(config: &Config) {
+    let mut value = toml::Value::try_from(config).unwrap_or_else(|e| fail(e));
+    redact(&mut value);
+    // Collector headers usually carry credentials too
+    if let Some(toml::Value::Table(headers)) = value.get_mut("otel").and_then(|o| o.get_mut("headers")) {
+        headers.iter_mut().for_each(|(_, v)| *v = toml::Value::String(REDACTED.to_string()));
+    }
+    print!("{}", toml::to_string_pretty(&value).unwrap_or_else(|e| fail(e)));
+}
@@ -0,0 +1,258 @@
+//! Config preprocessing, applied to the parsed TOML before it becomes `Config`:
+//!
+//! 1. `${VAR}` (or `${VAR:-default}`) in any string is replaced from the
+//!    environment; `$${` is a literal `${`.
+//! 2. `CORTEX_PROXY_<SECTION>__<KEY>` variables override single keys, with `__`
+//!    between path segments and array indexes as numbers
+//!    (`CORTEX_PROXY_PROXY__PORT=9000`, `CORTEX_PROXY_UPSTREAMS__0__PAT=...`).
+//!    Segments match existing keys regardless of case; new keys are lower case.
+//! 3. Every secret field `<name>` can instead be given as `<name>_file` (file
+//!    contents) or `<name>_command` (stdout of a shell command).
+
+use regex::{Captures, Regex};
+use std::ffi::OsString;
+use std::process::Command;
+use std::sync::LazyLock;
+use toml::{Table, Value};
+
+use crate::validate::KeyError;
+
+/// Fields that hold credentials
+pub const SECRET_FIELDS: [&str; 4] = ["pat", "client_secret", "refresh_token", "private_key_passphrase"];
+
+const ENV_PREFIX: &str = "CORTEX_PROXY_";
+
+/// `$${` (literal) or `${NAME}` / `${NAME:-default}`
+static REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\$\$\{|\$\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap()
+});
+
+pub fn preprocess(config: &mut Value) -> Result<(), KeyError> {
+    interpolate(config, &|name| std::env::var(name).ok(), "")?;
+    // vars_os: an unrelated non-UTF-8 variable must not abort the proxy
+    apply_overrides(config, std::env::vars_os())?;
+    resolve_secrets(config, "")
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+/// Replaces `${VAR}` references in every string below `value`
+fn interpolate(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>, path: &str) -> Result<(), KeyError> {
+    match value {
+        Value::String(s) if s.contains("${") => {
+            let mut missing = None;
+            let replaced = REFERENCE.replace_all(s, |caps: &Captures| {
+                let Some(name) = caps.get(1) else { return "${".to_string() };
+                lookup(name.as_str())
+                    .or_else(|| caps.get(2).map(|d| d.as_str().to_string()))
+                    .unwrap_or_else(|| {
+                        missing.get_or_insert_with(|| name.as_str().to_string());
+                        String::new()
+                    })
+            });
+            if let Some(name) = missing {
+                return Err(KeyError::new(path, format!("environment variable {} is not set", name)));
+            }
+            *s = replaced.into_owned();
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate(item, lookup, &format!("{}[{}]", path, i))?;
+            }
+        }
+        Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                interpolate(item, lookup, &join(path, key))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Env values are read as TOML (numbers, booleans, arrays) and fall back to a plain string
+fn parse_override(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("v = {}", raw))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// The key of `table` that `segment` names, ignoring case (`log_level` for
+/// `LOG_LEVEL`, `claude-4-Sonnet` for `CLAUDE-4-SONNET`), else `segment` as given
+fn table_key(table: &Table, segment: &str) -> String {
+    table.keys().find(|key| key.eq_ignore_ascii_case(segment)).cloned().unwrap_or_else(|| segment.to_string())
+}
+
+fn apply_overrides(config: &mut Value, vars: impl Iterator<Item = (OsString, OsString)>) -> Result<(), KeyError> {
+    for (name, raw) in vars {
+        if !name.as_encoded_bytes().starts_with(ENV_PREFIX.as_bytes()) {
+            continue;
+        }
+        let name = name.into_string().map_err(|n| KeyError::new(n.to_string_lossy(), "variable name is not valid UTF-8"))?;
+        // Every key lives in a section, so names without `__` (CORTEX_PROXY_CONFIG,
+        // a client's CORTEX_PROXY_API_KEY) are not overrides
+        let Some(path) = name.strip_prefix(ENV_PREFIX).filter(|p| p.contains("__")) else { continue };
+        let raw = raw.into_string().map_err(|_| KeyError::new(&name, "value is not valid UTF-8"))?;
+        let segments: Vec<String> = path.split("__").map(|s| s.to_ascii_lowercase()).collect();
+        if segments.iter().any(|s| s.is_empty()) {
+            return Err(KeyError::new(name, "empty key segment"));
+        }
+        let mut node = &mut *config;
+        for (i, segment) in segments.iter().enumerate() {
+            let last = i + 1 == segments.len();
+            node = match node {
+                Value::Table(table) if last => {
+                    table.insert(table_key(table, segment), parse_override(&raw));
+                    break;
+                }
+                Value::Table(table) => table.entry(table_key(table, segment)).or_insert_with(|| Value::Table(Table::new())),
+                Value::Array(items) => {
+                    let index: usize = segment.parse().map_err(|_| KeyError::new(&name, format!("'{}' is an array, expected an index", segment)))?;
+                    let len = items.len();
+                    let item = items.get_mut(index).ok_or_else(|| KeyError::new(&name, format!("index {} out of range ({} entries)", index, len)))?;
+                    if last {
+                        *item = parse_override(&raw);
+                        break;
+                    }
+                    item
+                }
+                _ => return Err(KeyError::new(&name, format!("'{}' is not a table", segment))),
+            };
+        }
+    }
+    Ok(())
+}
+
+/// Output of a shell command, as used by `<secret>_command`
+fn run_command(command: &str) -> Result<String, String> {
+    #[cfg(windows)]
+    let output = Command::new("cmd").args(["/C", command]).output();
+    #[cfg(not(windows))]
+    let output = Command::new("sh").args(["-c", command]).output();
+    let output = output.map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("exited with {}: {}", output.status, stderr.trim()));
+    }
+    String::from_utf8(output.stdout).map_err(|_| "output is not UTF-8".to_string())
+}
+
+/// Replaces `<secret>_file` / `<secret>_command` keys with the secret itself, in every table
+fn resolve_secrets(value: &mut Value, path: &str) -> Result<(), KeyError> {
+    match value {
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                resolve_secrets(item, &format!("{}[{}]", path, i))?;
+            }
+        }
+        Value::Table(table) => {
+            for field in SECRET_FIELDS {
+                let sources = [format!("{}_file", field), format!("{}_command", field)];
+                let given: Vec<&String> = sources.iter().filter(|k| table.contains_key(k.as_str())).collect();
+                let Some(source) = given.first() else { continue };
+                let key = join(path, source);
+                if given.len() > 1 || table.contains_key(field) {
+                    return Err(KeyError::new(join(path, field), format!("set only one of {}, {}_file and {}_command", field, field, field)));
+                }
+                let Some(Value::String(arg)) = table.remove(source.as_str()) else {
+                    return Err(KeyError::new(key, "expected a string"));
+                };
+                let secret = if source.ends_with("_file") {
+                    std::fs::read_to_string(&arg).map_err(|e| KeyError::new(&key, format!("{}: {}", arg, e)))?
+                } else {
+                    run_command(&arg).map_err(|e| KeyError::new(&key, e))?
+                };
+                // Files and commands usually end with a newline that isn't part of the secret
+                table.insert(field.to_string(), Value::String(secret.trim_end_matches(['\r', '\n']).to_string()));
+            }
+            for (key, item) in table.iter_mut() {
+                resolve_secrets(item, &join(path, key))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Value {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn interpolates_environment_variables() {
+        let mut config = parse("[snowflake]\npat = \"${PAT}\"\nbase_url = \"https://${ACCOUNT:-demo}.snowflakecomputing.com/$${x}\"");
+        let lookup = |name: &str| (name == "PAT").then(|| "secret".to_string());
+        interpolate(&mut config, &lookup, "").unwrap();
+        assert_eq!(config["snowflake"]["pat"].as_str(), Some("secret"));
+        assert_eq!(config["snowflake"]["base_url"].as_str(), Some("https://demo.snowflakecomputing.com/${x}"));
+
+        let err = interpolate(&mut parse("[otel.headers]\nAuthorization = \"Bearer ${TOKEN}\""), &lookup, "").unwrap_err();
+        assert_eq!(err.to_string(), "otel.headers.Authorization: environment variable TOKEN is not set");
+    }
+
+    #[test]
+    fn applies_env_overrides() {
+        let mut config = parse("[proxy]\nport = 8766\n[[upstreams]]\nname = \"us\"\npat = \"old\"\n[otel.headers]\nAuthorization = \"old\"");
+        let vars = [
+            ("CORTEX_PROXY_PROXY__PORT", "9000"),
+            ("CORTEX_PROXY_PROXY__LOG_LEVEL", "debug"),
+            ("CORTEX_PROXY_UPSTREAMS__0__PAT", "new"),
+            ("CORTEX_PROXY_METRICS__ENABLED", "true"),
+            ("CORTEX_PROXY_OTEL__HEADERS__AUTHORIZATION", "Bearer new"),
+            ("CORTEX_PROXY_CONFIG", "/etc/cortex-proxy.toml"),
+            ("CORTEX_PROXY_API_KEY", "dummy"),
+            ("HOME", "/root"),
+        ];
+        apply_overrides(&mut config, vars.iter().map(|(k, v)| (k.into(), v.into()))).unwrap();
+        assert_eq!(config["proxy"]["port"].as_integer(), Some(9000));
+        assert_eq!(config["proxy"]["log_level"].as_str(), Some("debug"));
+        assert_eq!(config["upstreams"][0]["pat"].as_str(), Some("new"));
+        assert_eq!(config["metrics"]["enabled"].as_bool(), Some(true));
+        // Existing keys keep their case
+        assert_eq!(config["otel"]["headers"]["Authorization"].as_str(), Some("Bearer new"));
+        assert!(config["otel"]["headers"].get("authorization").is_none());
+        assert!(config.get("config").is_none() && config.get("api_key").is_none());
+
+        let bad = [("CORTEX_PROXY_UPSTREAMS__3__PAT".into(), "x".into())];
+        assert!(apply_overrides(&mut config, bad.into_iter()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_non_utf8_overrides_only() {
+        use std::os::unix::ffi::OsStringExt;
+        let mut config = parse("[proxy]\nport = 8766");
+        let invalid = || OsString::from_vec(b"\xff\xfe".to_vec());
+        // Other variables may hold anything
+        let vars = [("LANG_BYTES".into(), invalid()), (OsString::from_vec(b"X\xff".to_vec()), "x".into())];
+        apply_overrides(&mut config, vars.into_iter()).unwrap();
+
+        let vars = [("CORTEX_PROXY_PROXY__LOG_LEVEL".into(), invalid())];
+        let err = apply_overrides(&mut config, vars.into_iter()).unwrap_err();
+        assert_eq!(err.to_string(), "CORTEX_PROXY_PROXY__LOG_LEVEL: value is not valid UTF-8");
+    }
+
+    #[test]
+    fn resolves_secret_files_and_commands() {
+        let path = std::env::temp_dir().join(format!("cortex-proxy-pat-{}", std::process::id()));
+        std::fs::write(&path, "from-file\n").unwrap();
+        let mut config = parse(&format!(
+            "[snowflake]\npat_file = {:?}\n[snowflake.auth]\nclient_secret_command = \"echo from-command\"",
+            path.display().to_string()
+        ));
+        resolve_secrets(&mut config, "").unwrap();
+        assert_eq!(config["snowflake"]["pat"].as_str(), Some("from-file"));
+        assert!(config["snowflake"].get("pat_file").is_none());
+        assert_eq!(config["snowflake"]["auth"]["client_secret"].as_str(), Some("from-command"));
+
+        let mut both = parse("[snowflake]\npat = \"x\"\npat_command = \"echo y\"");
+        assert!(resolve_secrets(&mut both, "").is_err());
+    }
+}
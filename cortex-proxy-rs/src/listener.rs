@@ -0,0 +1,259 @@
+//! Client-facing listener: TCP (IPv4 or IPv6) or a Unix domain socket,
+//! optionally terminating TLS with a certificate and key from config
+
+use hyper::{body::Incoming, service::service_fn, Request};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+use crate::reload::LiveApp;
+use crate::shutdown::Shutdown;
+
+/// Time given to connections to send their final events after the shutdown deadline
+const FINAL_FLUSH: Duration = Duration::from_secs(2);
+
+/// Connections that haven't finished the TLS handshake by then are dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request extension: the client's address, None on a Unix socket
+#[derive(Clone, Copy)]
+pub struct Peer(pub Option<SocketAddr>);
+
+impl Peer {
+    /// On this machine: loopback TCP (IPv4-mapped included) or a Unix socket
+    pub fn is_local(&self) -> bool {
+        self.0.is_none_or(|addr| addr.ip().to_canonical().is_loopback())
+    }
+}
+
+/// `[proxy.tls]` section: PEM files
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Certificate chain, leaf first
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parses `[proxy] listen`: an IP ("127.0.0.1", "::"), an address with its own
+/// port ("0.0.0.0:9000", "[::1]:9000") or "unix:/path/to/sock".
+/// A bare IP is combined with `[proxy] port`.
+pub fn parse_listen(listen: &str, port: u16) -> Result<ListenAddr, String> {
+    let listen = listen.trim();
+    if let Some(path) = listen.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err("listen: \"unix:\" needs a socket path".to_string());
+        }
+        return Ok(ListenAddr::Unix(PathBuf::from(path)));
+    }
+    if let Ok(addr) = listen.parse::<SocketAddr>() {
+        return Ok(ListenAddr::Tcp(addr));
+    }
+    let host = listen.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(listen);
+    let ip = match host {
+        "localhost" => IpAddr::from([127, 0, 0, 1]),
+        _ => host.parse::<IpAddr>().map_err(|_| {
+            format!("invalid listen address '{}': expected an IP, IP:port or unix:/path", listen)
+        })?,
+    };
+    Ok(ListenAddr::Tcp(SocketAddr::new(ip, port)))
+}
+
+/// Loads the certificate chain and private key into a TLS acceptor (HTTP/2 and HTTP/1.1 via ALPN)
+pub fn tls_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("TLS cert {}: {}", config.cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("TLS cert {}: no certificates found", config.cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|e| format!("TLS key {}: {}", config.key.display(), e))?;
+    let mut server_config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| format!("TLS config: {}", e))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Binds `addr` and serves the current router of `app` until `shutdown` drains
+/// the open connections (see `shutdown`)
+pub async fn serve(addr: &ListenAddr, tls: Option<TlsAcceptor>, app: LiveApp, shutdown: Shutdown) -> Result<(), String> {
+    // Every connection task holds a sender; recv() returns None once all are gone
+    let (open, mut closed) = mpsc::channel::<()>(1);
+    let conn = Connection { tls, app, shutdown: shutdown.clone(), _open: open };
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await
+                .map_err(|e| format!("cannot listen on {}: {}", addr, e))?;
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, addr)) => {
+                            let _ = stream.set_nodelay(true);
+                            spawn_connection(stream, Peer(Some(addr)), conn.clone());
+                        }
+                        Err(e) => accept_error(e).await,
+                    },
+                    _ = shutdown.draining() => break,
+                }
+            }
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            remove_stale_socket(path)?;
+            let listener = tokio::net::UnixListener::bind(path)
+                .map_err(|e| format!("cannot listen on {}: {}", path.display(), e))?;
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => spawn_connection(stream, Peer(None), conn.clone()),
+                        Err(e) => accept_error(e).await,
+                    },
+                    _ = shutdown.draining() => break,
+                }
+            }
+            drop(listener);
+            let _ = std::fs::remove_file(path);
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => return Err("Unix domain sockets are not supported on this platform".to_string()),
+    }
+
+    drop(conn);
+    tokio::select! {
+        _ = closed.recv() => return Ok(()),
+        _ = tokio::time::sleep(shutdown.timeout) => shutdown.expire(),
+        _ = shutdown.deadline() => {}
+    }
+    if tokio::time::timeout(FINAL_FLUSH, closed.recv()).await.is_err() {
+        warn!("Closing connections that are still open");
+    }
+    Ok(())
+}
+
+/// A socket file left behind by a previous run would make bind fail
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)
+            .map_err(|e| format!("cannot remove stale socket {}: {}", path.display(), e)),
+        Ok(_) => Err(format!("{} exists and is not a socket", path.display())),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Accept fails transiently when out of file descriptors; back off instead of spinning
+async fn accept_error(e: std::io::Error) {
+    warn!("accept failed: {}", e);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+/// What each connection task needs; a clone lives as long as its connection
+#[derive(Clone)]
+struct Connection {
+    tls: Option<TlsAcceptor>,
+    app: LiveApp,
+    shutdown: Shutdown,
+    /// Dropped when the connection closes
+    _open: mpsc::Sender<()>,
+}
+
+fn spawn_connection<S>(stream: S, peer: Peer, conn: Connection)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        match conn.tls.clone() {
+            Some(acceptor) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => serve_connection(stream, peer, conn).await,
+                Ok(Err(e)) => debug!("TLS handshake failed: {}", e),
+                Err(_) => debug!("TLS handshake timed out"),
+            },
+            None => serve_connection(stream, peer, conn).await,
+        }
+    });
+}
+
+async fn serve_connection<S>(stream: S, peer: Peer, conn: Connection)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Each request goes to the router that is current when it arrives, so
+    // keep-alive connections pick up a reloaded config too
+    let app = conn.app.clone();
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(peer);
+        app.current().oneshot(req)
+    });
+    let shutdown = &conn.shutdown;
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+    tokio::pin!(connection);
+    let mut draining = false;
+    let result = loop {
+        tokio::select! {
+            result = connection.as_mut() => break result,
+            // Finish the request in flight, then close (HTTP/2: GOAWAY)
+            _ = shutdown.draining(), if !draining => {
+                draining = true;
+                connection.as_mut().graceful_shutdown();
+            }
+        }
+    };
+    if let Err(e) = result {
+        debug!("connection closed with error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(listen: &str) -> SocketAddr {
+        match parse_listen(listen, 8766).unwrap() {
+            ListenAddr::Tcp(addr) => addr,
+            ListenAddr::Unix(path) => panic!("unexpected unix:{}", path.display()),
+        }
+    }
+
+    #[test]
+    fn parses_listen_addresses() {
+        assert_eq!(tcp("127.0.0.1").to_string(), "127.0.0.1:8766");
+        assert_eq!(tcp("0.0.0.0:9000").to_string(), "0.0.0.0:9000");
+        assert_eq!(tcp("::1").to_string(), "[::1]:8766");
+        assert_eq!(tcp("[::]").to_string(), "[::]:8766");
+        assert_eq!(tcp("[::1]:9000").to_string(), "[::1]:9000");
+        assert_eq!(tcp("localhost").to_string(), "127.0.0.1:8766");
+        assert!(matches!(parse_listen("unix:/tmp/cortex.sock", 8766), Ok(ListenAddr::Unix(p)) if p == std::path::Path::new("/tmp/cortex.sock")));
+        assert!(parse_listen("unix:", 8766).is_err());
+        assert!(parse_listen("example.com", 8766).is_err());
+    }
+}
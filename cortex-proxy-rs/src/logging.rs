@@ -0,0 +1,91 @@
+//! Structured logging via `tracing`
+//!
+//! `[proxy] log_level` accepts the classic `debug`/`info`/`quiet` levels or any
+//! `tracing` filter directive (`RUST_LOG` overrides it). Conversation bodies are
+//! logged under their own target, which stays off unless `log_bodies = true`.
+//! Both can change on config reload; the format and OTLP export cannot.
+
+use futures::Stream;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::io::IsTerminal;
+use tracing::Span;
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer, Registry};
+
+use crate::telemetry::{self, OtelConfig};
+
+/// Target for events that contain prompts, completions or raw payloads
+pub const BODY_TARGET: &str = "cortex_proxy::body";
+
+fn filter_directives(base: &str, log_bodies: bool) -> String {
+    let base = match base {
+        "debug" => "info,cortex_proxy=debug",
+        "info" => "warn,cortex_proxy=info",
+        "quiet" => "warn",
+        other => other,
+    };
+    let body_level = if log_bodies { "debug" } else { "off" };
+    format!("{},{}={}", base, BODY_TARGET, body_level)
+}
+
+/// Console filter for `log_level` (or `RUST_LOG`, which takes precedence)
+fn env_filter(log_level: &str, log_bodies: bool) -> Result<EnvFilter, String> {
+    let base = std::env::var("RUST_LOG").unwrap_or_else(|_| log_level.to_string());
+    EnvFilter::try_new(filter_directives(&base, log_bodies))
+        .map_err(|e| format!("invalid log_level '{}': {}", base, e))
+}
+
+/// Handles kept for the lifetime of the process
+pub struct Logging {
+    /// OTLP tracer provider when `[otel]` export is enabled, flushed on shutdown
+    pub tracer_provider: Option<SdkTracerProvider>,
+    filter: reload::Handle<EnvFilter, Registry>,
+}
+
+impl Logging {
+    /// Swaps the console filter after a config reload
+    pub fn set_level(&self, log_level: &str, log_bodies: bool) -> Result<(), String> {
+        let filter = env_filter(log_level, log_bodies)?;
+        self.filter.reload(filter).map_err(|e| e.to_string())
+    }
+}
+
+/// Checks `log_level` without applying it
+pub fn validate_level(log_level: &str, log_bodies: bool) -> Result<(), String> {
+    env_filter(log_level, log_bodies).map(|_| ())
+}
+
+/// Installs the global subscriber; `log_format` is `"text"` or `"json"`
+pub fn init(
+    log_level: &str,
+    log_format: &str,
+    log_bodies: bool,
+    otel: &OtelConfig,
+) -> Result<Logging, String> {
+    let (filter, filter_handle) = reload::Layer::new(env_filter(log_level, log_bodies)?);
+    let fmt_layer = tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal());
+    let fmt_layer = match log_format {
+        "json" => fmt_layer.json().with_current_span(true).with_span_list(false).boxed(),
+        "text" => fmt_layer.boxed(),
+        other => return Err(format!("invalid log_format '{}': expected \"text\" or \"json\"", other)),
+    };
+    let provider = telemetry::tracer_provider(otel)?;
+    // Per-layer filters: exported spans don't depend on the console log level
+    tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(filter))
+        .with(provider.as_ref().map(telemetry::layer))
+        .init();
+    Ok(Logging { tracer_provider: provider, filter: filter_handle })
+}
+
+/// Enters `span` on every poll, so events emitted while a response body streams
+/// are attributed to the request that produced it
+pub fn instrument_stream<S>(stream: S, span: Span) -> impl Stream<Item = S::Item> + Send
+where
+    S: Stream + Send + 'static,
+{
+    let mut stream = Box::pin(stream);
+    futures::stream::poll_fn(move |cx| {
+        let _entered = span.enter();
+        stream.as_mut().poll_next(cx)
+    })
+}
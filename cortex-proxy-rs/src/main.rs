@@ -6,179 +6,430 @@
 
 use axum::{
     body::Body,
-    extract::State,
+    extract::{Extension, MatchedPath, Query, State},
     http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{any, get, post},
     Router,
 };
 use bytes::Bytes;
+use clap::Parser;
 use futures::StreamExt;
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::{env, fs, path::PathBuf, sync::Arc, time::Instant};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::sync::{mpsc, oneshot};
 use tower_http::cors::{Any, CorsLayer};
+use tracing::{debug, info, warn, Instrument, Span};
 
-#[derive(Deserialize)]
+mod auth;
+mod check;
+mod cli;
+mod config_env;
+mod credentials;
+mod listener;
+mod logging;
+mod metrics;
+mod models;
+mod reload;
+mod routing;
+mod shutdown;
+mod sse;
+mod telemetry;
+mod upstream;
+mod usage;
+mod validate;
+
+use auth::{AuthConfig, AuthError};
+use cli::{Cli, Command};
+use credentials::SnowflakeAuth;
+use listener::{Peer, TlsConfig};
+use logging::BODY_TARGET;
+use metrics::{Metrics, MetricsConfig};
+use models::{default_model_catalog, default_model_rules, ModelInfo, ModelMapper, ModelRule, Reasoning};
+use reload::{LiveApp, ReloadRequest};
+use routing::{HealthConfig, RouteRule, UpstreamConfig, Upstreams};
+use shutdown::Shutdown;
+use sse::SseDecoder;
+use telemetry::OtelConfig;
+use upstream::{send_with_fallback, RetryConfig, RouteContext};
+use usage::{RequestTags, UsageConfig, UsageLedger};
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Config {
     proxy: ProxyConfig,
     snowflake: SnowflakeConfig,
     #[serde(default)]
     model_map: std::collections::HashMap<String, String>,
+    #[serde(default = "default_model_catalog")]
+    model_catalog: Vec<ModelInfo>,
+    #[serde(default)]
+    model_rules: Vec<ModelRule>,
+    /// Cortex model -> models to try, in order, when it is unavailable or throttled
+    #[serde(default)]
+    model_fallbacks: std::collections::HashMap<String, Vec<String>>,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    otel: OtelConfig,
+    #[serde(default)]
+    usage: UsageConfig,
+    #[serde(default)]
+    auth: AuthConfig,
+    /// Named Snowflake accounts; when empty, `[snowflake]` base_url/pat is the only upstream
+    #[serde(default)]
+    upstreams: Vec<UpstreamConfig>,
+    #[serde(default)]
+    routes: Vec<RouteRule>,
+    #[serde(default)]
+    upstream_health: HealthConfig,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ProxyConfig {
+    /// IP ("127.0.0.1", "::"), IP:port or "unix:/path/to/sock"
+    #[serde(default = "default_listen")]
+    listen: String,
     #[serde(default = "default_port")]
     port: u16,
+    /// Terminate TLS on the listener
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    /// "debug", "info", "quiet" or a tracing filter directive
     #[serde(default = "default_log_level")]
     log_level: String,
+    /// "text" or "json"
+    #[serde(default = "default_log_format")]
+    log_format: String,
+    /// Log prompts, completions and raw payloads (off by default)
+    #[serde(default)]
+    log_bodies: bool,
     #[serde(default = "default_timeout")]
     timeout_secs: u64,
     #[serde(default = "default_pool_size")]
     connection_pool_size: usize,
+    #[serde(default)]
+    retry: RetryConfig,
+    /// Reload the config when the file changes (SIGHUP and /admin/reload always work)
+    #[serde(default = "default_watch_config")]
+    watch_config: bool,
+    /// How long in-flight requests may run after SIGTERM before open streams are cut off
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout_secs: u64,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct SnowflakeConfig {
-    base_url: String,
-    pat: String,
+    #[serde(default)]
+    base_url: Option<String>,
+    #[serde(default)]
+    pat: Option<String>,
+    /// Key-pair auth instead of `pat`
+    #[serde(default)]
+    auth: Option<SnowflakeAuth>,
     #[serde(default = "default_model")]
     default_model: String,
+    /// Model used when a client name matches no alias, catalog entry or rule (default: pass-through)
+    #[serde(default)]
+    fallback_model: Option<String>,
+    /// Map dated Claude names (e.g. claude-sonnet-4-5-20250929) onto Cortex models after `[[model_rules]]`
+    #[serde(default = "default_builtin_model_rules")]
+    builtin_model_rules: bool,
 }
 
+fn default_listen() -> String { "127.0.0.1".to_string() }
 fn default_port() -> u16 { 8766 }
 fn default_log_level() -> String { "info".to_string() }
+fn default_log_format() -> String { "text".to_string() }
 fn default_model() -> String { "claude-4-sonnet".to_string() }
+fn default_builtin_model_rules() -> bool { true }
 fn default_timeout() -> u64 { 300 }
 fn default_pool_size() -> usize { 10 }
+fn default_watch_config() -> bool { true }
+fn default_shutdown_timeout() -> u64 { 30 }
 
 #[derive(Clone)]
 struct AppState {
     client: Client,
-    base_url: String,
-    auth_header: String,
+    upstreams: Arc<Upstreams>,
     default_model: String,
-    log_level: LogLevel,
-    model_map: std::collections::HashMap<String, String>,
-}
-
-#[derive(Clone, Copy, PartialEq, PartialOrd)]
-enum LogLevel {
-    Debug = 0,
-    Info = 1,
-    Quiet = 2,
+    model_map: ModelMapper,
+    model_catalog: Vec<ModelInfo>,
+    retry: RetryConfig,
+    model_fallbacks: std::collections::HashMap<String, Vec<String>>,
+    metrics: Arc<Metrics>,
+    usage: Option<Arc<UsageLedger>>,
+    usage_path: PathBuf,
+    auth: AuthConfig,
+    /// Metrics path when it is guarded like `/admin/*` (`[metrics] require_admin`)
+    admin_metrics_path: Option<String>,
+    /// Triggers a config reload (`POST /admin/reload`)
+    reload: mpsc::UnboundedSender<ReloadRequest>,
+    shutdown: Shutdown,
 }
 
-impl AppState {
-    fn log(&self, level: LogLevel, msg: &str) {
-        if level >= self.log_level {
-            println!("{}", msg);
-        }
+/// `explicit` is `--config` / `CORTEX_PROXY_CONFIG`; otherwise the first default location that exists
+fn find_config_path(explicit: Option<&Path>) -> Result<PathBuf, String> {
+    if let Some(path) = explicit {
+        return match path.exists() {
+            true => Ok(path.to_path_buf()),
+            false => Err(format!("config file {} not found", path.display())),
+        };
     }
+    default_config_paths().into_iter().find(|p| p.exists())
+        .ok_or_else(|| "Config not found (run `cortex-proxy init` to create one)".to_string())
 }
 
-fn find_config_path() -> Option<PathBuf> {
-    let args: Vec<String> = env::args().collect();
-    if let Some(idx) = args.iter().position(|a| a == "--config") {
-        if let Some(path) = args.get(idx + 1) {
-            let p = PathBuf::from(path);
-            if p.exists() { return Some(p); }
-        }
-    }
-    if let Ok(path) = env::var("CORTEX_PROXY_CONFIG") {
-        let p = PathBuf::from(path);
-        if p.exists() { return Some(p); }
-    }
-    for path_opt in [
+fn default_config_paths() -> Vec<PathBuf> {
+    [
         dirs::config_dir().map(|d| d.join("cortex-proxy/config.toml")),
         dirs::home_dir().map(|d| d.join(".config/cortex-proxy/config.toml")),
         Some(PathBuf::from("cortex-proxy.toml")),
-    ] {
-        if let Some(p) = path_opt {
-            if p.exists() { return Some(p); }
-        }
-    }
-    None
+    ].into_iter().flatten().collect()
+}
+
+fn load_config(explicit: Option<&Path>) -> (Config, PathBuf) {
+    find_config_path(explicit)
+        .and_then(|path| read_config(&path).map(|config| (config, path)))
+        .unwrap_or_else(|e| { eprintln!("Error: {}", e); std::process::exit(1); })
 }
 
-fn load_config() -> Result<Config, String> {
-    let config_path = find_config_path().ok_or("Config not found")?;
-    let content = fs::read_to_string(&config_path).map_err(|e| e.to_string())?;
-    let config: Config = toml::from_str(&content).map_err(|e| e.to_string())?;
-    println!("📄 Config: {}", config_path.display());
-    Ok(config)
+fn read_config(path: &Path) -> Result<Config, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    validate::parse_config(path, &content)
 }
 
 #[tokio::main]
 async fn main() {
-    let config = load_config().unwrap_or_else(|e| { eprintln!("Error: {}", e); std::process::exit(1); });
-    let log_level = match config.proxy.log_level.as_str() {
-        "debug" => LogLevel::Debug,
-        "quiet" => LogLevel::Quiet,
-        _ => LogLevel::Info,
-    };
+    let cli = Cli::parse();
+    let explicit = cli.config.as_deref();
+    match cli.command.unwrap_or(Command::Serve { shutdown_on_eof: false }) {
+        Command::Serve { shutdown_on_eof } => serve(load_config(explicit), shutdown_on_eof).await,
+        Command::Init { force } => cli::init_command(explicit, force),
+        Command::Check => cli::check_command(explicit),
+        Command::TestConnection { models } => cli::test_connection_command(&load_config(explicit).0, &models).await,
+        Command::PrintConfig => cli::print_config_command(&load_config(explicit).0),
+        Command::Usage { since, until, json } => {
+            usage_command(&load_config(explicit).0.usage, since.as_deref(), until.as_deref(), json)
+        }
+        Command::Keygen { name } => keygen_command(&name),
+    }
+}
 
-    let client = Client::builder()
-        .pool_max_idle_per_host(config.proxy.connection_pool_size)
-        .pool_idle_timeout(std::time::Duration::from_secs(60))
-        .timeout(std::time::Duration::from_secs(config.proxy.timeout_secs))
-        .tcp_keepalive(std::time::Duration::from_secs(30))
-        .tcp_nodelay(true)  // Disable Nagle's algorithm for lower latency
-        .gzip(true)         // Enable gzip compression
-        .build()
-        .unwrap();
+async fn serve((config, config_path): (Config, PathBuf), shutdown_on_eof: bool) {
+    let logging = logging::init(&config.proxy.log_level, &config.proxy.log_format, config.proxy.log_bodies, &config.otel)
+        .unwrap_or_else(|e| { eprintln!("Error: {}", e); std::process::exit(1); });
+    let _tracer_provider = logging.tracer_provider.clone();
+    info!("📄 Config: {}", config_path.display());
+    if let Some(endpoint) = &config.otel.endpoint {
+        info!("📡 OTLP traces: {}", endpoint);
+    }
 
-    let state = Arc::new(AppState {
-        client,
-        base_url: config.snowflake.base_url.trim_end_matches('/').to_string(),
-        auth_header: format!("Bearer {}", config.snowflake.pat),
-        default_model: config.snowflake.default_model,
-        log_level,
-        model_map: config.model_map,
+    let listen = listener::parse_listen(&config.proxy.listen, config.proxy.port)
+        .unwrap_or_else(|e| { eprintln!("Error: {}", e); std::process::exit(1); });
+    let tls = config.proxy.tls.as_ref().map(|tls| {
+        listener::tls_acceptor(tls).unwrap_or_else(|e| { eprintln!("Error: {}", e); std::process::exit(1); })
     });
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    match &listen {
+        listener::ListenAddr::Tcp(addr) => info!("🚀 Cortex Proxy on {}://{}", scheme, addr),
+        listener::ListenAddr::Unix(_) => info!("🚀 Cortex Proxy on {} ({})", listen, scheme),
+    }
+    info!("   /v1/messages (Anthropic) | /v1/messages/count_tokens | /chat/completions (OpenAI)");
+    if config.metrics.enabled {
+        info!("   {} (Prometheus)", config.metrics.path);
+    }
 
+    let metrics = Arc::new(Metrics::new());
+    let shutdown = Shutdown::new(std::time::Duration::from_secs(config.proxy.shutdown_timeout_secs));
+    tokio::spawn(shutdown::watch_signals(shutdown.clone()));
+    if shutdown_on_eof {
+        shutdown::watch_stdin(shutdown.clone());
+    }
+    let (reload_tx, reload_rx) = reload::channel();
+    let restart_settings = restart_only_settings(&config);
+    let watch_config = config.proxy.watch_config;
+    let app = build_app(config, metrics.clone(), reload_tx.clone(), shutdown.clone())
+        .unwrap_or_else(|e| { eprintln!("Error: {}", e); std::process::exit(1); });
+    let live = LiveApp::new(app);
+
+    let rebuild = {
+        let config_path = config_path.clone();
+        let shutdown = shutdown.clone();
+        move |_trigger: &str| -> Result<Router, String> {
+            let config = read_config(&config_path)?;
+            logging::validate_level(&config.proxy.log_level, config.proxy.log_bodies)?;
+            if restart_only_settings(&config) != restart_settings {
+                warn!("Changes to listen, port, tls, log_format, shutdown_timeout_secs, watch_config and [otel] take effect after a restart");
+            }
+            let (log_level, log_bodies) = (config.proxy.log_level.clone(), config.proxy.log_bodies);
+            let app = build_app(config, metrics.clone(), reload_tx.clone(), shutdown.clone())?;
+            logging.set_level(&log_level, log_bodies)?;
+            Ok(app)
+        }
+    };
+    tokio::spawn(reload::run(live.clone(), config_path, watch_config, reload_rx, rebuild));
+
+    if let Err(e) = listener::serve(&listen, tls, live, shutdown).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    usage::flush().await;
+    // Export the spans of the requests that just finished
+    if let Some(provider) = _tracer_provider {
+        let _ = provider.shutdown();
+    }
+    info!("👋 Stopped");
+}
+
+/// Settings that are only read at startup
+fn restart_only_settings(config: &Config) -> String {
+    let tls = config.proxy.tls.as_ref().map(|t| format!("{}|{}", t.cert.display(), t.key.display()));
+    format!(
+        "{}|{}|{:?}|{}|{}|{}|{:?}|{}|{:?}",
+        config.proxy.listen, config.proxy.port, tls, config.proxy.log_format, config.proxy.shutdown_timeout_secs,
+        config.proxy.watch_config, config.otel.endpoint, config.otel.protocol, config.otel.headers,
+    )
+}
+
+/// `[[upstreams]]`, preceded by `[snowflake]` as "default" when it has a base_url
+fn upstream_configs(config: &Config) -> Vec<UpstreamConfig> {
+    let mut configs = config.upstreams.clone();
+    if let Some(base_url) = &config.snowflake.base_url {
+        configs.insert(0, UpstreamConfig {
+            name: "default".to_string(),
+            base_url: base_url.clone(),
+            pat: config.snowflake.pat.clone(),
+            auth: config.snowflake.auth.clone(),
+            models: vec![],
+            weight: 1,
+        });
+    }
+    configs
+}
+
+/// Builds the shared state and router from `config`. Runs at startup and on
+/// every reload; `metrics` carries over so counters survive reloads.
+fn build_app(config: Config, metrics: Arc<Metrics>, reload: mpsc::UnboundedSender<ReloadRequest>, shutdown: Shutdown) -> Result<Router, String> {
+    let state = build_state(&config, metrics, reload, shutdown)?;
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers([header::HeaderName::from_static("x-request-id")]);
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(|| async { "OK" }))
         .route("/health", get(health_handler))
         .route("/v1/messages", post(anthropic_handler))
-        .route("/*path", any(openai_handler))
+        .route("/v1/messages/count_tokens", post(count_tokens_handler))
+        .route("/v1/models", get(models_handler))
+        .route("/models", get(models_handler))
+        .route("/v1/models/:model_id", get(model_handler))
+        .route("/admin/reload", post(admin_reload_handler))
+        .route("/*path", any(openai_handler));
+    if config.metrics.enabled {
+        app = app.route(&config.metrics.path, get(metrics_handler));
+    }
+    if config.usage.enabled {
+        app = app.route("/admin/usage", get(admin_usage_handler));
+    }
+    Ok(app
+        .layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), request_id_middleware))
         .layer(cors)
-        .with_state(state.clone());
-
-    let port = config.proxy.port;
-    println!("🚀 Cortex Proxy on http://localhost:{}", port);
-    println!("   /v1/messages (Anthropic) | /chat/completions (OpenAI)");
-    println!();
+        .with_state(state))
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+/// Cortex models the config names: metrics label these, and count any other model as "other"
+fn known_models(config: &Config) -> Vec<String> {
+    let mut models: Vec<String> = config.model_catalog.iter().map(|m| m.id.clone()).collect();
+    models.push(config.snowflake.default_model.clone());
+    models.extend(config.snowflake.fallback_model.clone());
+    models.extend(config.model_map.values().cloned());
+    let builtin = match config.snowflake.builtin_model_rules {
+        true => default_model_rules(),
+        false => Vec::new(),
+    };
+    // Regex targets with capture groups can expand to anything
+    models.extend(config.model_rules.iter().chain(&builtin).map(|r| r.target.clone()).filter(|t| !t.contains('$')));
+    for (model, chain) in &config.model_fallbacks {
+        models.push(model.clone());
+        models.extend(chain.iter().cloned());
+    }
+    models
 }
 
-// ============ Model Mapping ============
+/// HTTP client, upstreams, model mapping and ledger for one config generation
+fn build_state(config: &Config, metrics: Arc<Metrics>, reload: mpsc::UnboundedSender<ReloadRequest>, shutdown: Shutdown) -> Result<Arc<AppState>, String> {
+    let client = Client::builder()
+        .pool_max_idle_per_host(config.proxy.connection_pool_size)
+        .pool_idle_timeout(std::time::Duration::from_secs(60))
+        .timeout(std::time::Duration::from_secs(config.proxy.timeout_secs))
+        .tcp_keepalive(std::time::Duration::from_secs(30))
+        .tcp_nodelay(true)  // Disable Nagle's algorithm for lower latency
+        .gzip(true)         // Enable gzip compression
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let upstream_configs = upstream_configs(config);
+    let model_map = ModelMapper::new(
+        config.model_map.clone(),
+        &config.model_rules,
+        &config.model_catalog,
+        config.snowflake.fallback_model.clone(),
+        config.snowflake.builtin_model_rules,
+    )?;
 
-fn map_model(model: &str, model_map: &std::collections::HashMap<String, String>) -> String {
-    if let Some(mapped) = model_map.get(model) {
-        return mapped.clone();
+    let upstreams = Upstreams::new(&upstream_configs, &config.routes, config.upstream_health.clone())?;
+    if upstreams.len() > 1 {
+        let names: Vec<&str> = upstream_configs.iter().map(|u| u.name.as_str()).collect();
+        info!("🌐 Upstreams: {} ({} route rule(s))", names.join(", "), config.routes.len());
     }
-    let m = model.to_lowercase();
-    if m.contains("opus-4-5") || m.contains("4-5-opus") {
-        "claude-opus-4-5".to_string()
-    } else if m.contains("4-opus") || m.contains("opus-4") {
-        "claude-4-opus".to_string()
-    } else if m.contains("haiku") {
-        "claude-haiku-4-5".to_string()
-    } else if m.contains("3-5") && m.contains("sonnet") {
-        "claude-3-5-sonnet".to_string()
-    } else {
-        "claude-4-sonnet".to_string()
+    let usage = match config.usage.enabled {
+        true => Some(Arc::new(UsageLedger::open(&config.usage)?)),
+        false => None,
+    };
+    if config.usage.enabled {
+        info!("📒 Usage ledger: {}", config.usage.ledger_path().display());
     }
+    if config.auth.enabled {
+        info!("🔒 Client auth: {} key(s)", config.auth.keys.len());
+        if config.auth.keys.is_empty() {
+            warn!("[auth] is enabled without keys; every request will be rejected");
+        }
+    }
+
+    metrics.set_known_models(known_models(config));
+    Ok(Arc::new(AppState {
+        client,
+        upstreams: Arc::new(upstreams),
+        default_model: config.snowflake.default_model.clone(),
+        model_map,
+        model_catalog: config.model_catalog.clone(),
+        retry: config.proxy.retry.clone(),
+        model_fallbacks: config.model_fallbacks.clone(),
+        metrics,
+        usage,
+        usage_path: config.usage.ledger_path(),
+        auth: config.auth.clone(),
+        admin_metrics_path: (config.metrics.enabled && config.metrics.require_admin).then(|| config.metrics.path.clone()),
+        reload,
+        shutdown,
+    }))
 }
 
 // ============ Tool Conversation Validation ============
@@ -194,7 +445,7 @@ fn validate_tool_conversation(messages: &[Value]) -> Result<(), String> {
             // Check if there are unfulfilled tool calls from a previous assistant message
             // (this can happen in multi-turn conversations)
             if !pending_tool_ids.is_empty() {
-                eprintln!("DEBUG VALIDATION: Warning - unfulfilled tool calls before new assistant message at idx {}: {:?}", idx, pending_tool_ids);
+                debug!("Unfulfilled tool calls before new assistant message at idx {}: {:?}", idx, pending_tool_ids);
             }
             // Clear pending and collect new tool_call IDs
             pending_tool_ids.clear();
@@ -202,7 +453,7 @@ fn validate_tool_conversation(messages: &[Value]) -> Result<(), String> {
                 for tc in tcs {
                     if let Some(id) = tc.get("id").and_then(|i| i.as_str()) {
                         pending_tool_ids.push(id.to_string());
-                        eprintln!("DEBUG VALIDATION: Found tool_call id={} at message idx {}", id, idx);
+                        debug!("Found tool_call id={} at message idx {}", id, idx);
                     }
                 }
             }
@@ -210,41 +461,136 @@ fn validate_tool_conversation(messages: &[Value]) -> Result<(), String> {
             if let Some(id) = msg.get("tool_call_id").and_then(|i| i.as_str()) {
                 if pending_tool_ids.contains(&id.to_string()) {
                     pending_tool_ids.retain(|x| x != id);
-                    eprintln!("DEBUG VALIDATION: Matched tool result for id={} at message idx {}", id, idx);
+                    debug!("Matched tool result for id={} at message idx {}", id, idx);
                 } else {
-                    eprintln!("DEBUG VALIDATION: Warning - tool result for unknown id={} at message idx {}", id, idx);
+                    debug!("Tool result for unknown id={} at message idx {}", id, idx);
                 }
             }
         } else if role == "user" || role == "system" {
             // User/system messages don't affect tool call tracking
             // But if we have pending tool calls and hit a user message, that's unusual
             if !pending_tool_ids.is_empty() && role == "user" {
-                eprintln!("DEBUG VALIDATION: Warning - user message at idx {} with pending tool calls: {:?}", idx, pending_tool_ids);
+                debug!("User message at idx {} with pending tool calls: {:?}", idx, pending_tool_ids);
             }
         }
     }
     
     if !pending_tool_ids.is_empty() {
-        let err = format!("Unfulfilled tool calls at end of conversation: {:?}", pending_tool_ids);
-        eprintln!("DEBUG VALIDATION ERROR: {}", err);
-        return Err(err);
+        return Err(format!("Unfulfilled tool calls at end of conversation: {:?}", pending_tool_ids));
     }
     
-    eprintln!("DEBUG VALIDATION: All tool calls have matching results");
+    debug!("All tool calls have matching results");
     Ok(())
 }
 
 // ============ Anthropic -> OpenAI Conversion ============
 
+/// Maps Anthropic `thinking: {type: "enabled", budget_tokens}` to the reasoning
+/// parameter the model's catalog entry names, or drops it when there is none
+fn apply_thinking(openai_req: &mut Value, thinking: &Value, model: &str, reasoning: Option<Reasoning>) {
+    if thinking.get("type").and_then(|t| t.as_str()) != Some("enabled") {
+        return;
+    }
+    let budget = thinking.get("budget_tokens").and_then(|b| b.as_u64()).unwrap_or(1024);
+    match reasoning {
+        Some(Reasoning::Thinking) => {
+            openai_req["thinking"] = json!({"type": "enabled", "budget_tokens": budget});
+        }
+        Some(Reasoning::ReasoningEffort) => {
+            let effort = match budget {
+                0..=4096 => "low",
+                4097..=16384 => "medium",
+                _ => "high",
+            };
+            openai_req["reasoning_effort"] = json!(effort);
+        }
+        None => debug!("Model {} has no reasoning parameter in the catalog, ignoring thinking", model),
+    }
+}
+
+/// Asks for a trailing usage chunk on streams to models whose catalog entry sets
+/// `stream_usage`; other streams fall back to the estimate from `estimate_input_tokens`
+fn request_stream_usage(openai_req: &mut Value, model: &str, model_map: &ModelMapper) {
+    let streaming = openai_req.get("stream").and_then(|s| s.as_bool()).unwrap_or(false);
+    if streaming && model_map.supports_stream_usage(model) {
+        openai_req["stream_options"] = json!({"include_usage": true});
+    }
+}
+
+/// Maps Anthropic `tool_choice` (`auto`, `any`, `tool`, `none`) to the OpenAI shape
+fn anthropic_tool_choice_to_openai(tool_choice: &Value) -> Option<Value> {
+    match tool_choice.get("type").and_then(|t| t.as_str())? {
+        "auto" => Some(json!("auto")),
+        "any" => Some(json!("required")),
+        "none" => Some(json!("none")),
+        "tool" => {
+            let name = tool_choice.get("name").and_then(|n| n.as_str())?;
+            Some(json!({"type": "function", "function": {"name": name}}))
+        }
+        _ => None,
+    }
+}
+
+/// Attaches prior-turn thinking to an OpenAI assistant message. Only the text is
+/// sent: thinking signatures are Anthropic's and Cortex has no field for them.
+fn attach_reasoning(message: &mut Value, reasoning: &Option<String>) {
+    if let Some(text) = reasoning {
+        message["reasoning_content"] = json!(text);
+    }
+}
+
+/// Converts an Anthropic `image` block (base64 or URL source) into an OpenAI `image_url` part
+fn anthropic_image_to_openai(block: &Value) -> Option<Value> {
+    let source = block.get("source")?;
+    let url = match source.get("type").and_then(|t| t.as_str()) {
+        Some("base64") => {
+            let media_type = source.get("media_type").and_then(|m| m.as_str()).unwrap_or("image/png");
+            let data = source.get("data").and_then(|d| d.as_str())?;
+            format!("data:{};base64,{}", media_type, data)
+        }
+        Some("url") => source.get("url").and_then(|u| u.as_str())?.to_string(),
+        _ => return None,
+    };
+    Some(json!({"type": "image_url", "image_url": {"url": url}}))
+}
+
+/// Splits `tool_result` content into its text and any image parts it carries
+fn tool_result_content(content: Option<&Value>) -> (String, Vec<Value>) {
+    match content {
+        Some(Value::String(s)) => (s.clone(), vec![]),
+        Some(Value::Array(arr)) => {
+            let mut parts: Vec<String> = vec![];
+            let mut images: Vec<Value> = vec![];
+            for item in arr {
+                match item.get("type").and_then(|t| t.as_str()) {
+                    Some("text") => {
+                        if let Some(text) = item.get("text").and_then(|t| t.as_str()) {
+                            parts.push(text.to_string());
+                        }
+                    }
+                    Some("image") => match anthropic_image_to_openai(item) {
+                        Some(image) => images.push(image),
+                        None => warn!("Dropping a tool_result image with an unsupported source: {}", item.get("source").map(|s| s.to_string()).unwrap_or_default()),
+                    },
+                    _ => parts.push(serde_json::to_string(item).unwrap_or_default()),
+                }
+            }
+            (parts.join("\n"), images)
+        }
+        Some(other) => (serde_json::to_string(other).unwrap_or_default(), vec![]),
+        None => (String::new(), vec![]),
+    }
+}
+
 fn anthropic_to_openai(
     body: &[u8],
     default_model: &str,
-    model_map: &std::collections::HashMap<String, String>,
+    model_map: &ModelMapper,
 ) -> Result<(Value, bool), String> {
     let req: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
     
     let model = req.get("model").and_then(|m| m.as_str()).unwrap_or(default_model);
-    let snowflake_model = map_model(model, model_map);
+    let snowflake_model = model_map.map(model);
     let is_streaming = req.get("stream").and_then(|s| s.as_bool()).unwrap_or(false);
     let max_tokens = req.get("max_tokens").and_then(|m| m.as_u64()).unwrap_or(4096);
     
@@ -267,6 +613,8 @@ fn anthropic_to_openai(
     
     // Track tool_call info (ID -> name) for tool_results and reordering
     let mut pending_tool_calls: Vec<Value> = vec![];
+    // Thinking from a tool-calling turn, attached to the first replayed tool_call message
+    let mut pending_reasoning: Option<String> = None;
     let mut pending_tool_call_ids: Vec<String> = vec![];
     let mut tool_id_to_name: std::collections::HashMap<String, String> = std::collections::HashMap::new();
     
@@ -281,10 +629,16 @@ fn anthropic_to_openai(
                     messages.push(json!({"role": role, "content": s}));
                 }
                 Some(Value::Array(blocks)) => {
-                    // Handle content blocks (text, tool_use, tool_result)
+                    // Handle content blocks (text, image, tool_use, tool_result)
                     let mut text_parts: Vec<String> = vec![];
+                    // Ordered text/image parts, used when the message carries images
+                    let mut content_parts: Vec<Value> = vec![];
+                    let mut image_count = 0usize;
                     let mut tool_calls: Vec<Value> = vec![];
                     let mut tool_results: Vec<(String, String)> = vec![]; // (tool_use_id, content)
+                    let mut tool_images: Vec<(String, Vec<Value>)> = vec![]; // (tool_use_id, images)
+                    let mut thinking_parts: Vec<String> = vec![];
+                    let mut redacted_thinking = 0usize;
                     
                     for block in blocks {
                         let block_type = block.get("type").and_then(|t| t.as_str()).unwrap_or("");
@@ -293,6 +647,32 @@ fn anthropic_to_openai(
                             "text" => {
                                 if let Some(text) = block.get("text").and_then(|t| t.as_str()) {
                                     text_parts.push(text.to_string());
+                                    content_parts.push(json!({"type": "text", "text": text}));
+                                }
+                            }
+                            "thinking" => {
+                                // Prior-turn thinking is replayed as reasoning_content; the
+                                // signature stays with the client, Cortex has no use for it
+                                if let Some(text) = block.get("thinking").and_then(|t| t.as_str()) {
+                                    thinking_parts.push(text.to_string());
+                                }
+                            }
+                            "redacted_thinking" => {
+                                // Encrypted by Anthropic's API; nothing Cortex can read
+                                redacted_thinking += 1;
+                            }
+                            "image" if role == "assistant" => {
+                                // OpenAI assistant messages are text-only
+                                warn!("Dropping an image block from an assistant message; Cortex accepts images only from the user");
+                            }
+                            "image" => {
+                                // Anthropic image (base64 or url) -> OpenAI image_url part
+                                match anthropic_image_to_openai(block) {
+                                    Some(image) => {
+                                        content_parts.push(image);
+                                        image_count += 1;
+                                    }
+                                    None => warn!("Dropping an image block with an unsupported source: {}", block.get("source").map(|s| s.to_string()).unwrap_or_default()),
                                 }
                             }
                             "tool_use" => {
@@ -301,7 +681,7 @@ fn anthropic_to_openai(
                                 let name = block.get("name").and_then(|n| n.as_str()).unwrap_or("").to_string();
                                 let input = block.get("input").cloned().unwrap_or(json!({}));
                                 
-                                eprintln!("DEBUG TOOL_USE: Found tool_use block - id={} name={}", id, name);
+                                debug!("Found tool_use block id={} name={}", id, name);
                                 
                                 // Store mapping from ID to name
                                 tool_id_to_name.insert(id.clone(), name.clone());
@@ -318,51 +698,57 @@ fn anthropic_to_openai(
                             "tool_result" => {
                                 // Collect tool_result for later
                                 let tool_use_id = block.get("tool_use_id").and_then(|i| i.as_str()).unwrap_or("").to_string();
-                                let result_content = block.get("content");
-                                let result_text = match result_content {
-                                    Some(Value::String(s)) => s.clone(),
-                                    Some(Value::Array(arr)) => {
-                                        let mut parts: Vec<String> = vec![];
-                                        for item in arr {
-                                            if item.get("type").and_then(|t| t.as_str()) == Some("text") {
-                                                if let Some(text) = item.get("text").and_then(|t| t.as_str()) {
-                                                    parts.push(text.to_string());
-                                                }
-                                            } else {
-                                                parts.push(serde_json::to_string(item).unwrap_or_default());
-                                            }
-                                        }
-                                        parts.join("\n")
-                                    }
-                                    Some(other) => serde_json::to_string(other).unwrap_or_default(),
-                                    None => String::new(),
-                                };
+                                let (mut result_text, images) = tool_result_content(block.get("content"));
                                 
-                                eprintln!("DEBUG TOOL_RESULT: Found tool_result block - tool_use_id={} content_len={}", 
-                                    tool_use_id, result_text.len());
+                                debug!("Found tool_result block tool_use_id={} content_len={} images={}",
+                                    tool_use_id, result_text.len(), images.len());
                                 
+                                // OpenAI tool messages are text-only, so images ride along in a follow-up
+                                // user message; the tool message says so, and the images are labelled
+                                if !images.is_empty() {
+                                    if !result_text.is_empty() {
+                                        result_text.push('\n');
+                                    }
+                                    result_text.push_str(&format!("[{} image(s) attached below]", images.len()));
+                                    tool_images.push((tool_use_id.clone(), images));
+                                }
                                 tool_results.push((tool_use_id, result_text));
                             }
-                            _ => {}
+                            other => warn!("Dropping unsupported '{}' content block from a {} message", other, role),
                         }
                     }
                     
+                    if redacted_thinking > 0 {
+                        warn!("Dropping {} redacted_thinking block(s) from a {} message; Cortex cannot decrypt them", redacted_thinking, role);
+                    }
+                    
                     // For assistant messages: add with tool_calls if present
                     if role == "assistant" {
                         let content = text_parts.join("");
+                        let reasoning = if thinking_parts.is_empty() {
+                            None
+                        } else {
+                            Some(thinking_parts.join(""))
+                        };
                         if !tool_calls.is_empty() {
                             // If assistant includes text plus tool calls, emit text first
                             if !content.is_empty() {
-                                messages.push(json!({"role": "assistant", "content": content.clone()}));
+                                let mut text_msg = json!({"role": "assistant", "content": content.clone()});
+                                attach_reasoning(&mut text_msg, &reasoning);
+                                messages.push(text_msg);
+                            } else {
+                                pending_reasoning = reasoning;
                             }
                             pending_tool_call_ids = tool_calls.iter()
                                 .filter_map(|tc| tc.get("id").and_then(|i| i.as_str()).map(|s| s.to_string()))
                                 .collect();
                             pending_tool_calls = tool_calls;
                             
-                            eprintln!("DEBUG ASSISTANT: Queued {} tool_calls for sequential emit", pending_tool_calls.len());
-                        } else if !content.is_empty() {
-                            messages.push(json!({"role": "assistant", "content": content}));
+                            debug!("Queued {} tool_calls for sequential emit", pending_tool_calls.len());
+                        } else if !content.is_empty() || reasoning.is_some() {
+                            let mut text_msg = json!({"role": "assistant", "content": content});
+                            attach_reasoning(&mut text_msg, &reasoning);
+                            messages.push(text_msg);
                         }
                     } else {
                         // User message - emit tool_results as OpenAI tool messages
@@ -386,19 +772,21 @@ fn anthropic_to_openai(
                             }
                             
                             if !pending_tool_calls.is_empty() {
-                                eprintln!("DEBUG TOOL_MSGS: Sequentially emitting {} tool_calls with results", ordered_results.len());
+                                debug!("Sequentially emitting {} tool_calls with results", ordered_results.len());
                                 for (tool_use_id, result_text) in ordered_results.iter() {
                                     if let Some(tc) = pending_tool_calls.iter().find(|tc| {
                                         tc.get("id").and_then(|i| i.as_str()) == Some(tool_use_id.as_str())
                                     }).cloned() {
-                                        messages.push(json!({
+                                        let mut tool_call_msg = json!({
                                             "role": "assistant",
                                             "content": Value::Null,
                                             "tool_calls": [tc]
-                                        }));
+                                        });
+                                        attach_reasoning(&mut tool_call_msg, &pending_reasoning.take());
+                                        messages.push(tool_call_msg);
                                     }
                                     let tool_name = tool_id_to_name.get(tool_use_id).cloned().unwrap_or_default();
-                                    eprintln!("DEBUG TOOL_MSG: Adding tool message - tool_call_id={} name={}", tool_use_id, tool_name);
+                                    debug!("Adding tool message tool_call_id={} name={}", tool_use_id, tool_name);
                                     messages.push(json!({
                                         "role": "tool",
                                         "tool_call_id": tool_use_id,
@@ -409,13 +797,13 @@ fn anthropic_to_openai(
                                 pending_tool_calls.clear();
                                 pending_tool_call_ids.clear();
                             } else {
-                                eprintln!("DEBUG TOOL_MSGS: Pushing {} tool messages in order: {:?}", 
-                                    ordered_results.len(), 
+                                debug!("Pushing {} tool messages in order: {:?}",
+                                    ordered_results.len(),
                                     ordered_results.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>());
                                 
                                 for (tool_use_id, result_text) in ordered_results {
                                     let tool_name = tool_id_to_name.get(&tool_use_id).cloned().unwrap_or_default();
-                                    eprintln!("DEBUG TOOL_MSG: Adding tool message - tool_call_id={} name={}", tool_use_id, tool_name);
+                                    debug!("Adding tool message tool_call_id={} name={}", tool_use_id, tool_name);
                                     messages.push(json!({
                                         "role": "tool",
                                         "tool_call_id": tool_use_id,
@@ -427,7 +815,16 @@ fn anthropic_to_openai(
                         }
                         let combined_text = text_parts.join("");
                         let trimmed_text = combined_text.trim();
-                        if !trimmed_text.is_empty() && tool_results.is_empty() {
+                        if image_count > 0 || !tool_images.is_empty() {
+                            // Images need the multimodal content-part form; the user's own
+                            // content comes first, then each tool result's images under a label
+                            let mut parts = content_parts;
+                            for (tool_use_id, images) in tool_images {
+                                parts.push(json!({"type": "text", "text": format!("Image(s) from tool result {}:", tool_use_id)}));
+                                parts.extend(images);
+                            }
+                            messages.push(json!({"role": "user", "content": parts}));
+                        } else if !trimmed_text.is_empty() && tool_results.is_empty() {
                             messages.push(json!({"role": role, "content": combined_text}));
                         } else if !trimmed_text.is_empty() && !tool_results.is_empty() {
                             // User message has both tool_results and meaningful text
@@ -448,7 +845,6 @@ fn anthropic_to_openai(
         "stream": is_streaming,
         "max_completion_tokens": max_tokens
     });
-    
     // Convert tools from Anthropic format to OpenAI format
     if let Some(tools) = req.get("tools").and_then(|t| t.as_array()) {
         let openai_tools: Vec<Value> = tools.iter().map(|tool| {
@@ -468,21 +864,37 @@ fn anthropic_to_openai(
         
         if !openai_tools.is_empty() {
             openai_req["tools"] = json!(openai_tools);
+            
+            if let Some(tool_choice) = req.get("tool_choice") {
+                if let Some(choice) = anthropic_tool_choice_to_openai(tool_choice) {
+                    openai_req["tool_choice"] = choice;
+                }
+                let disable_parallel = tool_choice.get("disable_parallel_tool_use").and_then(|d| d.as_bool()).unwrap_or(false);
+                if disable_parallel && model_map.supports_parallel_tool_calls(&snowflake_model) {
+                    openai_req["parallel_tool_calls"] = json!(false);
+                }
+            }
         }
     }
     
+    // Map extended thinking onto Cortex reasoning parameters
+    if let Some(thinking) = req.get("thinking") {
+        let reasoning = model_map.info(&snowflake_model).and_then(|m| m.reasoning);
+        apply_thinking(&mut openai_req, thinking, &snowflake_model, reasoning);
+    }
+    
     // Copy other params
     if let Some(temp) = req.get("temperature") { openai_req["temperature"] = temp.clone(); }
     if let Some(top_p) = req.get("top_p") { openai_req["top_p"] = top_p.clone(); }
     if let Some(stop) = req.get("stop_sequences") { openai_req["stop"] = stop.clone(); }
     
     // Debug logging
-    eprintln!("DEBUG CONVERTED OpenAI messages: {}", serde_json::to_string_pretty(&messages).unwrap_or_default());
-    eprintln!("DEBUG TOOL_ID_MAP: Known tool IDs -> names: {:?}", tool_id_to_name);
+    debug!(target: BODY_TARGET, "Converted OpenAI messages: {}", serde_json::to_string_pretty(&messages).unwrap_or_default());
+    debug!("Known tool IDs -> names: {:?}", tool_id_to_name);
     
     // Validate tool call/result pairing before sending to Snowflake
     if let Err(e) = validate_tool_conversation(&messages) {
-        eprintln!("DEBUG VALIDATION FAILED: {}", e);
+        warn!("Tool conversation validation failed: {}", e);
     }
     
     Ok((openai_req, is_streaming))
@@ -490,13 +902,27 @@ fn anthropic_to_openai(
 
 // ============ OpenAI -> Anthropic Response Conversion ============
 
-fn openai_to_anthropic(openai_resp: &Value, model: &str, req_id: u128) -> Value {
+/// Extracts reasoning text from an OpenAI message or stream delta (`reasoning_content`,
+/// the field `attach_reasoning` sends back on later turns). Cortex returns no thinking
+/// signature, so thinking blocks carry an empty one.
+fn reasoning_from_openai(message: &Value) -> Option<&str> {
+    message.get("reasoning_content").and_then(|v| v.as_str())
+}
+
+fn openai_to_anthropic(openai_resp: &Value, model: &str, unique_id: &str) -> Value {
     let choice = &openai_resp["choices"][0];
     let message = &choice["message"];
     let finish_reason = choice.get("finish_reason").and_then(|f| f.as_str());
     
     let mut content: Vec<Value> = vec![];
     
+    // Reasoning comes first, as an Anthropic thinking block
+    if let Some(thinking) = reasoning_from_openai(message) {
+        if !thinking.is_empty() {
+            content.push(json!({"type": "thinking", "thinking": thinking, "signature": ""}));
+        }
+    }
+    
     // Add text content if present
     if let Some(text) = message.get("content").and_then(|c| c.as_str()) {
         if !text.is_empty() {
@@ -507,7 +933,10 @@ fn openai_to_anthropic(openai_resp: &Value, model: &str, req_id: u128) -> Value
     // Convert tool_calls to tool_use blocks
     if let Some(tool_calls) = message.get("tool_calls").and_then(|t| t.as_array()) {
         for tc in tool_calls {
-            let id = tc.get("id").and_then(|i| i.as_str()).unwrap_or("");
+            let id = match tc.get("id").and_then(|i| i.as_str()).filter(|i| !i.is_empty()) {
+                Some(id) => id.to_string(),
+                None => format!("toolu_{}_{}", unique_id, content.len()),
+            };
             let func = &tc["function"];
             let name = func.get("name").and_then(|n| n.as_str()).unwrap_or("");
             let args_str = func.get("arguments").and_then(|a| a.as_str()).unwrap_or("{}");
@@ -535,283 +964,690 @@ fn openai_to_anthropic(openai_resp: &Value, model: &str, req_id: u128) -> Value
         }
     };
     
-    let usage = &openai_resp["usage"];
-    
     json!({
-        "id": format!("msg_{:06}", req_id),
+        "id": format!("msg_{}", unique_id),
         "type": "message",
         "role": "assistant",
         "content": content,
         "model": model,
         "stop_reason": stop_reason,
-        "usage": {
-            "input_tokens": usage.get("prompt_tokens").and_then(|p| p.as_u64()).unwrap_or(0),
-            "output_tokens": usage.get("completion_tokens").and_then(|c| c.as_u64()).unwrap_or(0)
-        }
+        "usage": anthropic_usage(&openai_resp["usage"])
     })
 }
 
+/// Converts OpenAI usage to Anthropic usage. Anthropic's `input_tokens` excludes
+/// cached tokens, which are reported separately as cache reads/writes.
+fn anthropic_usage(usage: &Value) -> Value {
+    let field = |v: &Value, k: &str| v.get(k).and_then(|n| n.as_u64());
+    let details = &usage["prompt_tokens_details"];
+    let cache_read = field(usage, "cache_read_input_tokens").or_else(|| field(details, "cached_tokens")).unwrap_or(0);
+    let cache_write = field(usage, "cache_creation_input_tokens").or_else(|| field(details, "cache_creation_tokens")).unwrap_or(0);
+    let prompt_tokens = field(usage, "prompt_tokens").unwrap_or(0);
+    
+    let mut out = json!({
+        "input_tokens": prompt_tokens.saturating_sub(cache_read + cache_write),
+        "output_tokens": field(usage, "completion_tokens").unwrap_or(0),
+    });
+    if cache_read > 0 || cache_write > 0 {
+        out["cache_read_input_tokens"] = json!(cache_read);
+        out["cache_creation_input_tokens"] = json!(cache_write);
+    }
+    out
+}
+
 // ============ Health Check Handler ============
 
 async fn health_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let upstreams: Vec<Value> = state.upstreams.status().into_iter()
+        .map(|(name, healthy)| json!({"name": name, "healthy": healthy}))
+        .collect();
     axum::Json(json!({
         "status": "ok",
         "service": "cortex-proxy",
         "default_model": state.default_model,
+        "upstreams": upstreams,
     }))
 }
 
+// ============ Metrics Handler ============
+
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/openmetrics-text; version=1.0.0; charset=utf-8")],
+        state.metrics.render(),
+    )
+}
+
+/// True when a streamed OpenAI delta carries output (text, reasoning or a tool call)
+fn delta_has_content(delta: &Value) -> bool {
+    let non_empty = |key: &str| delta.get(key).and_then(|v| v.as_str()).is_some_and(|s| !s.is_empty());
+    non_empty("content")
+        || reasoning_from_openai(delta).is_some_and(|r| !r.is_empty())
+        || delta.get("tool_calls").and_then(|t| t.as_array()).is_some_and(|t| !t.is_empty())
+}
+
+// ============ Config Reload ============
+
+async fn admin_reload_handler(State(state): State<Arc<AppState>>) -> Response {
+    let (reply, outcome) = oneshot::channel();
+    if state.reload.send(reply).is_err() {
+        return error_response(503, "config reload is unavailable");
+    }
+    match outcome.await {
+        Ok(Ok(())) => axum::Json(json!({"status": "reloaded"})).into_response(),
+        Ok(Err(e)) => error_response(400, &e),
+        Err(_) => error_response(503, "config reload is unavailable"),
+    }
+}
+
+// ============ Usage Ledger ============
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    since: Option<String>,
+    until: Option<String>,
+}
+
+/// Daily usage per model and client, read from the ledger
+async fn admin_usage_handler(State(state): State<Arc<AppState>>, Query(query): Query<UsageQuery>) -> Response {
+    let path = state.usage_path.clone();
+    let rows = tokio::task::spawn_blocking(move || {
+        usage::summarize(&path, query.since.as_deref(), query.until.as_deref())
+    }).await;
+    match rows {
+        Ok(Ok(rows)) => axum::Json(json!({"data": rows})).into_response(),
+        Ok(Err(e)) => error_response(500, &e),
+        Err(e) => error_response(500, &e.to_string()),
+    }
+}
+
+/// `cortex-proxy usage [--since YYYY-MM-DD] [--until YYYY-MM-DD] [--json]`
+fn usage_command(config: &UsageConfig, since: Option<&str>, until: Option<&str>, json: bool) {
+    let path = config.ledger_path();
+    let rows = usage::summarize(&path, since, until)
+        .unwrap_or_else(|e| { eprintln!("Error: {}", e); std::process::exit(1); });
+    if json {
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap_or_default());
+    } else {
+        print!("{}", usage::format_table(&rows));
+    }
+}
+
+// ============ Models Handler ============
+
+/// Lists the default model, every model_map alias and the catalog, deduplicated by id
+fn list_models(state: &AppState) -> Vec<ModelInfo> {
+    let lookup = |id: &str| state.model_catalog.iter().find(|m| m.id == id).cloned();
+    let mut models: Vec<ModelInfo> = vec![];
+    let mut push = |model: ModelInfo| {
+        if !models.iter().any(|m| m.id == model.id) {
+            models.push(model);
+        }
+    };
+    
+    let default = lookup(&state.default_model).unwrap_or(ModelInfo {
+        id: state.default_model.clone(),
+        ..Default::default()
+    });
+    push(default);
+    
+    let mut aliases: Vec<(&String, &String)> = state.model_map.aliases.iter().collect();
+    aliases.sort();
+    for (alias, target) in aliases {
+        // Aliases inherit the metadata of the model they map to
+        push(ModelInfo { id: alias.clone(), ..lookup(target).unwrap_or_default() });
+    }
+    
+    for model in &state.model_catalog {
+        push(model.clone());
+    }
+    models
+}
+
+/// Anthropic clients identify themselves with `anthropic-version`
+fn wants_anthropic_format(headers: &HeaderMap) -> bool {
+    headers.contains_key("anthropic-version")
+}
+
+fn anthropic_model_entry(model: &ModelInfo) -> Value {
+    json!({
+        "type": "model",
+        "id": model.id,
+        "display_name": model.display_name.clone().unwrap_or_else(|| model.id.clone()),
+        "context_window": model.context_window,
+        "max_output_tokens": model.max_output_tokens,
+    })
+}
+
+fn openai_model_entry(model: &ModelInfo) -> Value {
+    json!({
+        "id": model.id,
+        "object": "model",
+        "created": 0,
+        "owned_by": "snowflake-cortex",
+        "context_window": model.context_window,
+        "max_output_tokens": model.max_output_tokens,
+    })
+}
+
+async fn models_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let models = list_models(&state);
+    if wants_anthropic_format(&headers) {
+        axum::Json(json!({
+            "data": models.iter().map(anthropic_model_entry).collect::<Vec<_>>(),
+            "has_more": false,
+            "first_id": models.first().map(|m| m.id.clone()),
+            "last_id": models.last().map(|m| m.id.clone()),
+        })).into_response()
+    } else {
+        axum::Json(json!({
+            "object": "list",
+            "data": models.iter().map(openai_model_entry).collect::<Vec<_>>(),
+        })).into_response()
+    }
+}
+
+async fn model_handler(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(model_id): axum::extract::Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let anthropic = wants_anthropic_format(&headers);
+    match list_models(&state).into_iter().find(|m| m.id == model_id) {
+        Some(model) if anthropic => axum::Json(anthropic_model_entry(&model)).into_response(),
+        Some(model) => axum::Json(openai_model_entry(&model)).into_response(),
+        None if anthropic => anthropic_error(404, &format!("model: {}", model_id)),
+        None => error_response(404, &format!("The model '{}' does not exist", model_id)),
+    }
+}
+
+// ============ Request IDs ============
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Per-request IDs, inserted into request extensions by `request_id_middleware`
+#[derive(Clone)]
+struct RequestId {
+    /// Echoed in `x-request-id` and logged: the client's own ID when it sent a valid one
+    id: String,
+    /// Always generated here; message and tool-use IDs derive from it so they
+    /// stay unique when a client reuses its `x-request-id`
+    unique: String,
+}
+
+/// Monotonic counter plus a random suffix, unique across restarts and replicas
+fn new_request_id() -> String {
+    let n = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:08x}{:012x}", n, rand::random::<u64>() & 0xffff_ffff_ffff)
+}
+
+/// Header/log safe value of `name` of at most `max_len` bytes, if the client sent one
+fn safe_header(headers: &HeaderMap, name: &str, max_len: usize) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?.trim();
+    let valid = !value.is_empty()
+        && value.len() <= max_len
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    valid.then(|| value.to_string())
+}
+
+/// Accepts a client-supplied `x-request-id` of up to 64 characters from `[A-Za-z0-9._-]`
+fn client_request_id(headers: &HeaderMap) -> Option<String> {
+    safe_header(headers, "x-request-id", 64)
+}
+
+/// Assigns the request ID and wraps the request in a span; handlers fill in
+/// `model` and `stream`, and every event logged while handling it carries them
+async fn request_id_middleware(State(state): State<Arc<AppState>>, mut req: Request<Body>, next: Next) -> Response {
+    let unique = new_request_id();
+    let id = client_request_id(req.headers()).unwrap_or_else(|| unique.clone());
+    // Matched route template keeps the metrics label set bounded
+    let route = req.extensions().get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    // Self-reported label for usage accounting
+    let client = safe_header(req.headers(), "x-cortex-client", 128).unwrap_or_else(|| "anonymous".to_string());
+    let tags = RequestTags::new(&id, req.uri().path(), &client, state.usage.clone());
+    req.extensions_mut().insert(RequestId { id: id.clone(), unique });
+    req.extensions_mut().insert(tags.clone());
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        request_id = %id,
+        method = %req.method(),
+        route = %req.uri().path(),
+        model = tracing::field::Empty,
+        stream = tracing::field::Empty,
+        status = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+    );
+    telemetry::set_remote_parent(&span, req.headers());
+    let start = Instant::now();
+    let mut resp = next.run(req).instrument(span.clone()).await;
+    // For streams this is time to first byte; the handler logs the full duration
+    span.record("status", resp.status().as_u16());
+    if resp.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    span.record("latency_ms", start.elapsed().as_millis() as u64);
+    span.in_scope(|| info!("request completed"));
+    tags.set_status(resp.status().as_u16());
+    state.metrics.record_request(&route, &tags, resp.status().as_u16());
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        resp.headers_mut().insert("x-request-id", value);
+    }
+    resp
+}
+
+// ============ Client Authentication ============
+
+/// Routes that stay open with `[auth]` enabled (the GUI polls /health)
+const UNAUTHENTICATED_ROUTES: [&str; 2] = ["/", "/health"];
+
+/// Rejects requests without a valid proxy key and tags the rest with the key's name.
+/// Without `[auth]`, admin endpoints only answer clients on this machine.
+async fn auth_middleware(State(state): State<Arc<AppState>>, req: Request<Body>, next: Next) -> Response {
+    let path = req.uri().path();
+    let anthropic = path.starts_with("/v1/messages") || wants_anthropic_format(req.headers());
+    let admin_only = path.starts_with("/admin/") || state.admin_metrics_path.as_deref() == Some(path);
+    if !state.auth.enabled {
+        let local = req.extensions().get::<Peer>().is_some_and(Peer::is_local);
+        if admin_only && !local {
+            let msg = "admin endpoints only accept local clients while [auth] is disabled";
+            warn!("Rejected request: {}", msg);
+            return if anthropic { anthropic_error(403, msg) } else { openai_error(403, "permission_error", None, msg) };
+        }
+        return next.run(req).await;
+    }
+    if UNAUTHENTICATED_ROUTES.contains(&path) {
+        return next.run(req).await;
+    }
+    let key = match state.auth.authenticate(req.headers()) {
+        Ok(key) => key,
+        Err(e) => {
+            let msg = match e {
+                AuthError::Missing => "missing API key: send it in x-api-key or Authorization: Bearer",
+                AuthError::Invalid => "invalid API key",
+            };
+            warn!("Rejected request: {}", msg);
+            return if anthropic {
+                anthropic_error(401, msg)
+            } else {
+                openai_error(401, "invalid_request_error", Some("invalid_api_key"), msg)
+            };
+        }
+    };
+    if admin_only && !key.admin {
+        let msg = "this API key is not allowed to use admin endpoints";
+        return if anthropic { anthropic_error(403, msg) } else { openai_error(403, "permission_error", None, msg) };
+    }
+    if let Some(tags) = req.extensions().get::<RequestTags>() {
+        tags.set_authenticated_client(&key.name);
+    }
+    next.run(req).await
+}
+
+/// `cortex-proxy keygen [name]`: prints a new key and the config entry holding its hash
+fn keygen_command(name: &str) {
+    let key = auth::generate_key();
+    println!("API key (shown once, give it to the client):\n  {}\n", key);
+    println!("Add to your config:\n");
+    println!("[[auth.keys]]\nname = \"{}\"\nsha256 = \"{}\"", name, auth::hash_key(&key));
+}
+
 // ============ Anthropic API Handler ============
 
 async fn anthropic_handler(
     State(state): State<Arc<AppState>>,
+    Extension(RequestId { id: req_id, unique }): Extension<RequestId>,
+    Extension(tags): Extension<RequestTags>,
+    headers: HeaderMap,
     body: Bytes,
 ) -> Response {
     let start = Instant::now();
-    let req_id = (start.elapsed().as_nanos() % 1_000_000) as u128;
+    let req_id = req_id.as_str();
     
-    // Log request immediately
-    eprintln!("DEBUG [{:06}] Request received, body size: {} bytes", req_id, body.len());
+    debug!("Request received, body size: {} bytes", body.len());
     
     // Convert Anthropic -> OpenAI format
-    let (openai_req, is_streaming) = match anthropic_to_openai(&body, &state.default_model, &state.model_map) {
+    let converted = tracing::info_span!("convert")
+        .in_scope(|| anthropic_to_openai(&body, &state.default_model, &state.model_map));
+    let (openai_req, is_streaming) = match converted {
         Ok(r) => r,
         Err(e) => {
-            state.log(LogLevel::Info, &format!("[{:06}] Parse error: {}", req_id, e));
+            warn!("Parse error: {}", e);
             return anthropic_error(400, &e);
         }
     };
+    Span::current().record("stream", is_streaming);
+    tags.set_stream(is_streaming);
     
     let model = openai_req.get("model").and_then(|m| m.as_str()).unwrap_or("claude-4-sonnet");
-    state.log(LogLevel::Debug, &format!("[{:06}] OpenAI req: {}", req_id, openai_req));
+    debug!(target: BODY_TARGET, "OpenAI req: {}", openai_req);
     
     // Forward to Snowflake
-    let url = format!("{}/chat/completions", state.base_url);
     let accept = if is_streaming { "text/event-stream" } else { "application/json" };
     
-    let resp = match state.client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .header("Accept", accept)
-        .header("Accept-Encoding", "gzip")
-        .header("User-Agent", "cortex-proxy/1.0")
-        .header("Authorization", &state.auth_header)
-        .header("X-Snowflake-Authorization-Token-Type", "PROGRAMMATIC_ACCESS_TOKEN")
-        .json(&openai_req)
-        .send()
-        .await
-    {
+    let chat_span = telemetry::genai_span("chat", model, &openai_req);
+    let route = RouteContext { headers: &headers, tags: &tags };
+    let (result, served_model) = send_with_fallback(&state, model, &route, |m, upstream| {
+        let mut attempt_req = openai_req.clone();
+        attempt_req["model"] = json!(m);
+        request_stream_usage(&mut attempt_req, m, &state.model_map);
+        state.client
+            .post(format!("{}/chat/completions", upstream.base_url))
+            .header("Content-Type", "application/json")
+            .header("Accept", accept)
+            .header("Accept-Encoding", "gzip")
+            .header("User-Agent", "cortex-proxy/1.0")
+            .header("X-Request-ID", req_id)
+            .json(&attempt_req)
+    }).instrument(chat_span.clone()).await;
+    // Report the model that actually served the request
+    let model = served_model.as_str();
+    Span::current().record("model", model);
+    tags.set_model(model);
+    
+    let resp = match result {
         Ok(r) => r,
         Err(e) => {
-            state.log(LogLevel::Info, &format!("[{:06}] Upstream error: {}", req_id, e));
+            warn!("Upstream error: {}", e);
+            telemetry::record_error(&chat_span, "connection");
             return anthropic_error(502, &format!("Upstream error: {}", e));
         }
     };
     
     let status = resp.status();
-    let elapsed = start.elapsed().as_millis();
     
     if !status.is_success() {
+        telemetry::record_error(&chat_span, status.as_str());
         let error_body = resp.text().await.unwrap_or_default();
-        state.log(LogLevel::Info, &format!("[{:06}] HTTP {}: {}", req_id, status.as_u16(), &error_body[..error_body.len().min(200)]));
+        warn!(status = status.as_u16(), "Upstream HTTP {}", status.as_u16());
+        debug!(target: BODY_TARGET, "Upstream error body: {}", error_body);
         
         // Handle conversation complete
         let error_lower = error_body.to_lowercase();
         if status.as_u16() == 400 && (
             error_lower.contains("final position") || error_lower.contains("tool_result")
         ) {
-            return anthropic_complete(is_streaming, model);
+            state.metrics.record_synthetic_completion("anthropic");
+            return anthropic_complete(is_streaming, model, &unique);
         }
         return anthropic_error(status.as_u16(), &error_body);
     }
     
     if is_streaming {
         // Streaming response
-        eprintln!("DEBUG [{:06}] Starting streaming response", req_id);
+        debug!("Starting streaming response");
         let mut headers = HeaderMap::new();
         headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
         headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
         
-        let state_clone = state.clone();
         let model_owned = model.to_string();
+        let estimated_input_tokens = estimate_input_tokens(&openai_req);
+        let unique = unique.clone();
+        let metrics = state.metrics.clone();
+        let active_stream = metrics.stream_guard();
+        let tags = tags.clone();
+        let shutdown = state.shutdown.clone();
         
         let stream = async_stream::stream! {
             // Send message_start
             yield Ok::<_, std::io::Error>(Bytes::from(format!(
                 "event: message_start\ndata: {}\n\n",
                 json!({"type": "message_start", "message": {
-                    "id": format!("msg_{:06}", req_id),
+                    "id": format!("msg_{}", unique),
                     "type": "message",
                     "role": "assistant",
                     "content": [],
                     "model": model_owned,
                     "stop_reason": null,
-                    "usage": {"input_tokens": 0, "output_tokens": 0}
+                    // An estimate; message_delta re-sends input_tokens once Cortex reports usage
+                    "usage": {"input_tokens": estimated_input_tokens, "output_tokens": 0}
                 }})
             )));
             
-            let mut had_text_content = false;
-            let mut final_events_sent = false;
-            let mut buffer = String::new();
+            // Set once Cortex reports finish_reason; message_delta is sent after usage arrives
+            let mut stop_reason: Option<&str> = None;
+            let mut usage: Option<Value> = None;
+            // Streamed thinking, text and tool-call characters, for estimating output when Cortex reports no usage
+            let mut output_chars = 0usize;
             // Track tools by ID (since Snowflake returns all tools with index=0)
             // Map: tool_id -> anthropic_index
             let mut tool_indices: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
             // Track the current (most recent) tool being streamed for argument association
             let mut current_tool_id: Option<String> = None;
             let mut last_content_index: i32 = -1; // -1 = nothing started yet
+            // Type of the currently open block ("thinking", "text" or "tool_use")
+            let mut open_block_type: &str = "";
+            let mut next_index = 0usize;
             let mut tool_count = 0usize;
+            let mut first_token_seen = false;
+            let mut finish_reason: Option<String> = None;
+            let mut response_id: Option<String> = None;
+            let _active_stream = active_stream;
             
+            let mut decoder = SseDecoder::new();
             let mut byte_stream = resp.bytes_stream();
-            while let Some(chunk) = byte_stream.next().await {
-                match chunk {
-                    Ok(bytes) => {
-                        buffer.push_str(&String::from_utf8_lossy(&bytes));
+            let mut upstream_done = false;
+            while !upstream_done {
+                let next = tokio::select! {
+                    next = byte_stream.next() => Some(next),
+                    _ = shutdown.deadline() => None,
+                };
+                let events = match next {
+                    Some(Some(Ok(bytes))) => decoder.feed(&bytes),
+                    Some(Some(Err(e))) => {
+                        // A partial event may be truncated mid-line: drop it
+                        warn!("Stream error: {}", e);
+                        upstream_done = true;
+                        vec![]
+                    }
+                    Some(None) => {
+                        upstream_done = true;
+                        decoder.finish().into_iter().collect()
+                    }
+                    None => {
+                        warn!(latency_ms = start.elapsed().as_millis() as u64, "stream cut off by shutdown");
+                        yield Ok(Bytes::from(shutdown::ANTHROPIC_CUT_OFF_EVENT));
+                        return;
+                    }
+                };
+                
+                for event in events {
+                    let data = event.data.as_str();
+                    if data == "[DONE]" { continue; }
+                    
+                    if let Ok(chunk_data) = serde_json::from_str::<Value>(data) {
+                        debug!(target: BODY_TARGET, "OpenAI chunk: {}", data);
+                        let delta = &chunk_data["choices"][0]["delta"];
+                        let finish = chunk_data["choices"][0].get("finish_reason");
+                        if response_id.is_none() {
+                            response_id = chunk_data["id"].as_str().map(|s| s.to_string());
+                        }
+                        if !first_token_seen && delta_has_content(delta) {
+                            first_token_seen = true;
+                            metrics.observe_time_to_first_token(&model_owned, start.elapsed().as_secs_f64());
+                        }
                         
-                        while let Some(pos) = buffer.find("\n\n") {
-                            let line = buffer[..pos].to_string();
-                            buffer = buffer[pos + 2..].to_string();
-                            
-                            if !line.starts_with("data: ") { continue; }
-                            let data = &line[6..];
-                            if data == "[DONE]" { continue; }
-                            
-                            if let Ok(chunk_data) = serde_json::from_str::<Value>(data) {
-                                eprintln!("DEBUG OPENAI CHUNK: {}", data);
-                                let delta = &chunk_data["choices"][0]["delta"];
-                                let finish = chunk_data["choices"][0].get("finish_reason");
-                                
-                                // Handle text content
-                                if let Some(text) = delta.get("content").and_then(|c| c.as_str()) {
-                                    if !text.is_empty() {
-                                        if !had_text_content {
-                                            yield Ok(Bytes::from(format!(
-                                                "event: content_block_start\ndata: {}\n\n",
-                                                json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}})
-                                            )));
-                                            had_text_content = true;
-                                            last_content_index = 0;
-                                        }
+                        // Handle reasoning content -> thinking block
+                        if let Some(thinking) = reasoning_from_openai(delta).filter(|t| !t.is_empty()) {
+                            output_chars += thinking.len();
+                            if open_block_type != "thinking" {
+                                if last_content_index >= 0 {
+                                    yield Ok(Bytes::from(format!(
+                                        "event: content_block_stop\ndata: {}\n\n",
+                                        json!({"type": "content_block_stop", "index": last_content_index})
+                                    )));
+                                }
+                                last_content_index = next_index as i32;
+                                next_index += 1;
+                                open_block_type = "thinking";
+                                yield Ok(Bytes::from(format!(
+                                    "event: content_block_start\ndata: {}\n\n",
+                                    json!({"type": "content_block_start", "index": last_content_index, "content_block": {"type": "thinking", "thinking": "", "signature": ""}})
+                                )));
+                            }
+                            yield Ok(Bytes::from(format!(
+                                "event: content_block_delta\ndata: {}\n\n",
+                                json!({"type": "content_block_delta", "index": last_content_index, "delta": {"type": "thinking_delta", "thinking": thinking}})
+                            )));
+                        }
+                        
+                        // Handle text content
+                        if let Some(text) = delta.get("content").and_then(|c| c.as_str()) {
+                            if !text.is_empty() {
+                                output_chars += text.len();
+                                if open_block_type != "text" {
+                                    if last_content_index >= 0 {
                                         yield Ok(Bytes::from(format!(
-                                            "event: content_block_delta\ndata: {}\n\n",
-                                            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": text}})
+                                            "event: content_block_stop\ndata: {}\n\n",
+                                            json!({"type": "content_block_stop", "index": last_content_index})
                                         )));
                                     }
+                                    last_content_index = next_index as i32;
+                                    next_index += 1;
+                                    open_block_type = "text";
+                                    yield Ok(Bytes::from(format!(
+                                        "event: content_block_start\ndata: {}\n\n",
+                                        json!({"type": "content_block_start", "index": last_content_index, "content_block": {"type": "text", "text": ""}})
+                                    )));
                                 }
+                                yield Ok(Bytes::from(format!(
+                                    "event: content_block_delta\ndata: {}\n\n",
+                                    json!({"type": "content_block_delta", "index": last_content_index, "delta": {"type": "text_delta", "text": text}})
+                                )));
+                            }
+                        }
+                        
+                        // Handle tool calls
+                        // NOTE: Snowflake returns ALL tools with index=0, so we track by ID instead
+                        if let Some(tool_calls) = delta.get("tool_calls").and_then(|t| t.as_array()) {
+                            for tc in tool_calls {
+                                let tc_id = tc.get("id").and_then(|i| i.as_str()).filter(|s| !s.is_empty());
+                                let tc_func = tc.get("function");
+                                let tc_name = tc_func.and_then(|f| f.get("name")).and_then(|n| n.as_str()).filter(|s| !s.is_empty());
                                 
-                                // Handle tool calls
-                                // NOTE: Snowflake returns ALL tools with index=0, so we track by ID instead
-                                if let Some(tool_calls) = delta.get("tool_calls").and_then(|t| t.as_array()) {
-                                    for tc in tool_calls {
-                                        let tc_id = tc.get("id").and_then(|i| i.as_str()).filter(|s| !s.is_empty());
-                                        let tc_func = tc.get("function");
-                                        let tc_name = tc_func.and_then(|f| f.get("name")).and_then(|n| n.as_str()).filter(|s| !s.is_empty());
-                                        
-                                        // Check if this is a NEW tool call (has id AND name)
-                                        if let (Some(id), Some(name)) = (tc_id, tc_name) {
-                                            // Close previous content block if any
-                                            if last_content_index >= 0 {
-                                                yield Ok(Bytes::from(format!(
-                                                    "event: content_block_stop\ndata: {}\n\n",
-                                                    json!({"type": "content_block_stop", "index": last_content_index})
-                                                )));
-                                            }
-                                            
-                                            // Calculate this tool's Anthropic index
-                                            let anthropic_index = if had_text_content { 1 + tool_count } else { tool_count };
-                                            tool_count += 1;
-                                            
-                                            // Store tool index by ID and set as current
-                                            tool_indices.insert(id.to_string(), anthropic_index);
-                                            current_tool_id = Some(id.to_string());
-                                            last_content_index = anthropic_index as i32;
-                                            
-                                            eprintln!("DEBUG STREAM: tool_use id={} name={} anthropic_index={}", id, name, anthropic_index);
-                                            yield Ok(Bytes::from(format!(
-                                                "event: content_block_start\ndata: {}\n\n",
-                                                json!({"type": "content_block_start", "index": anthropic_index, "content_block": {
-                                                    "type": "tool_use",
-                                                    "id": id,
-                                                    "name": name,
-                                                    "input": {}
-                                                }})
-                                            )));
-                                        }
-                                        
-                                        // Stream argument chunks - associate with current tool
-                                        if let Some(args) = tc_func.and_then(|f| f.get("arguments")).and_then(|a| a.as_str()) {
-                                            if !args.is_empty() {
-                                                // Use the current tool's index (arguments follow their tool immediately)
-                                                if let Some(ref tool_id) = current_tool_id {
-                                                    if let Some(&anthropic_index) = tool_indices.get(tool_id) {
-                                                        yield Ok(Bytes::from(format!(
-                                                            "event: content_block_delta\ndata: {}\n\n",
-                                                            json!({"type": "content_block_delta", "index": anthropic_index, "delta": {"type": "input_json_delta", "partial_json": args}})
-                                                        )));
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                
-                                // Handle finish
-                                if let Some(reason) = finish.and_then(|r| r.as_str()) {
-                                    // Close the last content block
-                                    if last_content_index >= 0 && !final_events_sent {
+                                // Check if this is a NEW tool call (has a name; id is generated if Cortex omits it)
+                                if let Some(name) = tc_name {
+                                    let id = tc_id.map(|i| i.to_string()).unwrap_or_else(|| format!("toolu_{}_{}", unique, tool_count));
+                                    let id = id.as_str();
+                                    // Close previous content block if any
+                                    if last_content_index >= 0 {
                                         yield Ok(Bytes::from(format!(
                                             "event: content_block_stop\ndata: {}\n\n",
                                             json!({"type": "content_block_stop", "index": last_content_index})
                                         )));
                                     }
                                     
-                                    let stop_reason = if tool_count > 0 || reason == "tool_calls" {
-                                        "tool_use"
-                                    } else {
-                                        match reason {
-                                            "stop" => "end_turn",
-                                            "length" | "max_tokens" => "max_tokens",
-                                            _ => "end_turn",
-                                        }
-                                    };
+                                    // Allocate this tool's Anthropic index
+                                    let anthropic_index = next_index;
+                                    next_index += 1;
+                                    tool_count += 1;
+                                    output_chars += name.len();
+                                    
+                                    // Store tool index by ID and set as current
+                                    tool_indices.insert(id.to_string(), anthropic_index);
+                                    current_tool_id = Some(id.to_string());
+                                    last_content_index = anthropic_index as i32;
+                                    open_block_type = "tool_use";
                                     
-                                    eprintln!("DEBUG STREAM: message_delta stop_reason={} tool_count={}", stop_reason, tool_count);
+                                    debug!("Stream tool_use id={} name={} anthropic_index={}", id, name, anthropic_index);
                                     yield Ok(Bytes::from(format!(
-                                        "event: message_delta\ndata: {}\n\n",
-                                        json!({"type": "message_delta", "delta": {"stop_reason": stop_reason}, "usage": {"output_tokens": 0}})
+                                        "event: content_block_start\ndata: {}\n\n",
+                                        json!({"type": "content_block_start", "index": anthropic_index, "content_block": {
+                                            "type": "tool_use",
+                                            "id": id,
+                                            "name": name,
+                                            "input": {}
+                                        }})
                                     )));
-                                    final_events_sent = true;
+                                }
+                                
+                                // Stream argument chunks - associate with current tool
+                                if let Some(args) = tc_func.and_then(|f| f.get("arguments")).and_then(|a| a.as_str()) {
+                                    if !args.is_empty() {
+                                        output_chars += args.len();
+                                        // Use the current tool's index (arguments follow their tool immediately)
+                                        if let Some(ref tool_id) = current_tool_id {
+                                            if let Some(&anthropic_index) = tool_indices.get(tool_id) {
+                                                yield Ok(Bytes::from(format!(
+                                                    "event: content_block_delta\ndata: {}\n\n",
+                                                    json!({"type": "content_block_delta", "index": anthropic_index, "delta": {"type": "input_json_delta", "partial_json": args}})
+                                                )));
+                                            }
+                                        }
+                                    }
                                 }
                             }
                         }
-                    }
-                    Err(e) => {
-                        state_clone.log(LogLevel::Info, &format!("[{:06}] Stream error: {}", req_id, e));
-                        break;
+                        
+                        // Usage arrives in a trailing chunk (stream_options.include_usage)
+                        if let Some(u) = chunk_data.get("usage").filter(|u| u.is_object()) {
+                            usage = Some(u.clone());
+                        }
+                        
+                        // Handle finish
+                        if let Some(reason) = finish.and_then(|r| r.as_str()) {
+                            finish_reason.get_or_insert_with(|| reason.to_string());
+                            // Close the last content block
+                            if last_content_index >= 0 && stop_reason.is_none() {
+                                yield Ok(Bytes::from(format!(
+                                    "event: content_block_stop\ndata: {}\n\n",
+                                    json!({"type": "content_block_stop", "index": last_content_index})
+                                )));
+                            }
+                            
+                            if stop_reason.is_none() {
+                                stop_reason = Some(if tool_count > 0 || reason == "tool_calls" {
+                                    "tool_use"
+                                } else {
+                                    match reason {
+                                        "stop" => "end_turn",
+                                        "length" | "max_tokens" => "max_tokens",
+                                        _ => "end_turn",
+                                    }
+                                });
+                            }
+                        }
                     }
                 }
             }
             
-            // Ensure we always emit content_block_stop and message_delta if content was started
-            if !final_events_sent {
-                if last_content_index >= 0 {
-                    yield Ok(Bytes::from(format!(
-                        "event: content_block_stop\ndata: {}\n\n",
-                        json!({"type": "content_block_stop", "index": last_content_index})
-                    )));
-                }
-                // If we streamed any tool calls, stop_reason should be "tool_use"
-                let stop_reason = if tool_count > 0 { "tool_use" } else { "end_turn" };
+            // Ensure we always emit content_block_stop if content was started
+            if stop_reason.is_none() && last_content_index >= 0 {
                 yield Ok(Bytes::from(format!(
-                    "event: message_delta\ndata: {}\n\n",
-                    json!({"type": "message_delta", "delta": {"stop_reason": stop_reason}, "usage": {"output_tokens": 0}})
+                    "event: content_block_stop\ndata: {}\n\n",
+                    json!({"type": "content_block_stop", "index": last_content_index})
                 )));
             }
+            // If we streamed any tool calls, stop_reason should be "tool_use"
+            let stop_reason = stop_reason.unwrap_or(if tool_count > 0 { "tool_use" } else { "end_turn" });
+            // message_delta waits for the trailing usage chunk so counts are real;
+            // without one, output is estimated like input (~4 characters per token)
+            let delta_usage = match &usage {
+                Some(u) => anthropic_usage(u),
+                None => json!({"input_tokens": estimated_input_tokens, "output_tokens": (output_chars as u64).div_ceil(4)}),
+            };
+            metrics.record_usage(&model_owned, &delta_usage);
+            match usage {
+                Some(_) => tags.set_usage(&delta_usage),
+                None => tags.set_estimated_usage(&delta_usage),
+            }
+            telemetry::record_response(&chat_span, &model_owned, response_id.as_deref(), finish_reason.as_deref(), usage.as_ref());
+            debug!("Stream message_delta stop_reason={} tool_count={}", stop_reason, tool_count);
+            yield Ok(Bytes::from(format!(
+                "event: message_delta\ndata: {}\n\n",
+                json!({"type": "message_delta", "delta": {"stop_reason": stop_reason}, "usage": delta_usage})
+            )));
             
             yield Ok(Bytes::from("event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n"));
-            state_clone.log(LogLevel::Info, &format!("[{:06}] /v1/messages stream=true {}ms", req_id, start.elapsed().as_millis()));
+            info!(latency_ms = start.elapsed().as_millis() as u64, "stream completed");
         };
         
-        return (StatusCode::OK, headers, Body::from_stream(stream)).into_response();
+        let stream = logging::instrument_stream(stream, tracing::info_span!("stream_relay"));
+        (StatusCode::OK, headers, Body::from_stream(stream)).into_response()
     } else {
         // Non-streaming
         let openai_resp: Value = match resp.json().await {
@@ -819,40 +1655,114 @@ async fn anthropic_handler(
             Err(e) => return anthropic_error(502, &format!("Invalid response: {}", e)),
         };
         
-        state.log(LogLevel::Debug, &format!("[{:06}] OpenAI resp: {}", req_id, openai_resp));
+        debug!(target: BODY_TARGET, "OpenAI resp: {}", openai_resp);
         
-        let anthropic_resp = openai_to_anthropic(&openai_resp, model, req_id);
-        state.log(LogLevel::Info, &format!("[{:06}] /v1/messages stream=false {}ms", req_id, elapsed));
+        telemetry::record_response(
+            &chat_span,
+            model,
+            openai_resp["id"].as_str(),
+            openai_resp["choices"][0]["finish_reason"].as_str(),
+            openai_resp.get("usage"),
+        );
+        let anthropic_resp = openai_to_anthropic(&openai_resp, model, &unique);
+        state.metrics.record_usage(model, &anthropic_resp["usage"]);
+        tags.set_usage(&anthropic_resp["usage"]);
         
-        return (
+        (
             StatusCode::OK,
             [(header::CONTENT_TYPE, "application/json")],
             serde_json::to_string(&anthropic_resp).unwrap_or_default(),
-        ).into_response();
+        ).into_response()
+    }
+}
+
+// ============ Token Counting Handler ============
+
+/// Rough per-image cost; Anthropic bills ~1600 tokens for a full-size image
+const IMAGE_TOKEN_ESTIMATE: u64 = 1600;
+
+/// Estimates prompt tokens for a converted request. Cortex exposes no tokenizer,
+/// so this uses ~4 characters per token plus a small per-message overhead.
+fn estimate_input_tokens(openai_req: &Value) -> u64 {
+    let mut chars = 0usize;
+    let mut tokens = 0u64;
+    
+    if let Some(messages) = openai_req.get("messages").and_then(|m| m.as_array()) {
+        for msg in messages {
+            tokens += 4;
+            match msg.get("content") {
+                Some(Value::String(s)) => chars += s.len(),
+                Some(Value::Array(parts)) => {
+                    for part in parts {
+                        if part.get("type").and_then(|t| t.as_str()) == Some("image_url") {
+                            tokens += IMAGE_TOKEN_ESTIMATE;
+                        } else if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
+                            chars += text.len();
+                        }
+                    }
+                }
+                _ => {}
+            }
+            if let Some(reasoning) = msg.get("reasoning_content").and_then(|r| r.as_str()) {
+                chars += reasoning.len();
+            }
+            if let Some(tool_calls) = msg.get("tool_calls") {
+                chars += tool_calls.to_string().len();
+            }
+        }
+    }
+    if let Some(tools) = openai_req.get("tools") {
+        chars += tools.to_string().len();
     }
+    
+    tokens + (chars as u64).div_ceil(4)
+}
+
+async fn count_tokens_handler(
+    State(state): State<Arc<AppState>>,
+    body: Bytes,
+) -> Response {
+    let (openai_req, _) = match anthropic_to_openai(&body, &state.default_model, &state.model_map) {
+        Ok(r) => r,
+        Err(e) => return anthropic_error(400, &e),
+    };
+    let input_tokens = estimate_input_tokens(&openai_req);
+    debug!("count_tokens input_tokens={}", input_tokens);
+    axum::Json(json!({"input_tokens": input_tokens})).into_response()
 }
 
 fn anthropic_error(code: u16, msg: &str) -> Response {
+    // Error types as documented for the Anthropic Messages API
+    let error_type = match code {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        529 => "overloaded_error",
+        _ => "api_error",
+    };
     (
         StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
         [(header::CONTENT_TYPE, "application/json")],
-        json!({"type": "error", "error": {"type": "api_error", "message": msg}}).to_string(),
+        json!({"type": "error", "error": {"type": error_type, "message": msg}}).to_string(),
     ).into_response()
 }
 
-fn anthropic_complete(is_streaming: bool, model: &str) -> Response {
+fn anthropic_complete(is_streaming: bool, model: &str, unique_id: &str) -> Response {
     if is_streaming {
         (StatusCode::OK, [(header::CONTENT_TYPE, "text/event-stream")],
-         format!("event: message_start\ndata: {{\"type\":\"message_start\",\"message\":{{\"id\":\"msg_done\",\"type\":\"message\",\"role\":\"assistant\",\"content\":[],\"model\":\"{}\",\"stop_reason\":null}}}}\n\n\
+         format!("event: message_start\ndata: {{\"type\":\"message_start\",\"message\":{{\"id\":\"msg_{}\",\"type\":\"message\",\"role\":\"assistant\",\"content\":[],\"model\":\"{}\",\"stop_reason\":null}}}}\n\n\
                   event: content_block_start\ndata: {{\"type\":\"content_block_start\",\"index\":0,\"content_block\":{{\"type\":\"text\",\"text\":\"\"}}}}\n\n\
                   event: content_block_delta\ndata: {{\"type\":\"content_block_delta\",\"index\":0,\"delta\":{{\"type\":\"text_delta\",\"text\":\"Done.\"}}}}\n\n\
                   event: content_block_stop\ndata: {{\"type\":\"content_block_stop\",\"index\":0}}\n\n\
                   event: message_delta\ndata: {{\"type\":\"message_delta\",\"delta\":{{\"stop_reason\":\"end_turn\"}}}}\n\n\
-                  event: message_stop\ndata: {{\"type\":\"message_stop\"}}\n\n", model)
+                  event: message_stop\ndata: {{\"type\":\"message_stop\"}}\n\n", unique_id, model)
         ).into_response()
     } else {
         (StatusCode::OK, [(header::CONTENT_TYPE, "application/json")],
-         json!({"id":"msg_done","type":"message","role":"assistant","content":[{"type":"text","text":"Done."}],"model":model,"stop_reason":"end_turn","usage":{"input_tokens":0,"output_tokens":1}}).to_string()
+         json!({"id":format!("msg_{}", unique_id),"type":"message","role":"assistant","content":[{"type":"text","text":"Done."}],"model":model,"stop_reason":"end_turn","usage":{"input_tokens":0,"output_tokens":1}}).to_string()
         ).into_response()
     }
 }
@@ -861,8 +1771,12 @@ fn anthropic_complete(is_streaming: bool, model: &str) -> Response {
 
 async fn openai_handler(State(state): State<Arc<AppState>>, req: Request<Body>) -> Response {
     let start = Instant::now();
-    let req_id = (start.elapsed().as_nanos() % 1_000_000) as u128;
+    let req_id = req.extensions().get::<RequestId>().map(|r| r.id.clone()).unwrap_or_else(new_request_id);
+    let req_id = req_id.as_str();
+    let tags = req.extensions().get::<RequestTags>().cloned()
+        .unwrap_or_else(|| RequestTags::new(req_id, req.uri().path(), "anonymous", None));
     let method = req.method().clone();
+    let headers = req.headers().clone();
     let mut path = req.uri().path().to_string();
     
     if path.ends_with("/completions") && !path.contains("/chat/") {
@@ -874,32 +1788,51 @@ async fn openai_handler(State(state): State<Arc<AppState>>, req: Request<Body>)
         Err(e) => return error_response(500, &e.to_string()),
     };
     
-    let (transformed, is_streaming) = transform_openai(&body, &state.model_map);
-    let url = format!("{}{}", state.base_url, path);
+    let (transformed, is_streaming) = tracing::info_span!("convert")
+        .in_scope(|| transform_openai(&body, &state.model_map));
+    Span::current().record("stream", is_streaming);
+    tags.set_stream(is_streaming);
     let accept = if is_streaming { "text/event-stream" } else { "application/json" };
     
-    let resp = match state.client.request(method, &url)
-        .header("Content-Type", "application/json")
-        .header("Accept", accept)
-        .header("Accept-Encoding", "gzip")
-        .header("User-Agent", "cortex-proxy/1.0")
-        .header("Authorization", &state.auth_header)
-        .header("X-Snowflake-Authorization-Token-Type", "PROGRAMMATIC_ACCESS_TOKEN")
-        .body(transformed)
-        .send()
-        .await
-    {
+    let transformed_json = serde_json::from_slice::<Value>(&transformed).unwrap_or(Value::Null);
+    let model = transformed_json.get("model").and_then(|m| m.as_str()).unwrap_or_default().to_string();
+    
+    let operation = if path.ends_with("/embeddings") { "embeddings" } else { "chat" };
+    let upstream_span = telemetry::genai_span(operation, &model, &transformed_json);
+    let route = RouteContext { headers: &headers, tags: &tags };
+    let (result, served_model) = send_with_fallback(&state, &model, &route, |m, upstream| {
+        let body = if m == model { transformed.clone() } else { replace_model(&transformed, m) };
+        state.client.request(method.clone(), format!("{}{}", upstream.base_url, path))
+            .header("Content-Type", "application/json")
+            .header("Accept", accept)
+            .header("Accept-Encoding", "gzip")
+            .header("User-Agent", "cortex-proxy/1.0")
+            .header("X-Request-ID", req_id)
+            .body(body)
+    }).instrument(upstream_span.clone()).await;
+    
+    Span::current().record("model", served_model.as_str());
+    tags.set_model(&served_model);
+    
+    let resp = match result {
         Ok(r) => r,
-        Err(e) => return error_response(502, &e.to_string()),
+        Err(e) => {
+            warn!("Upstream error: {}", e);
+            telemetry::record_error(&upstream_span, "connection");
+            return error_response(502, &e.to_string());
+        }
     };
     
     let status = resp.status();
     if !status.is_success() {
+        telemetry::record_error(&upstream_span, status.as_str());
         let body = resp.text().await.unwrap_or_default();
         if status.as_u16() == 400 && (body.contains("final position") || body.contains("tool_result")) {
+            state.metrics.record_synthetic_completion("openai");
             return synthetic_complete(is_streaming);
         }
-        state.log(LogLevel::Info, &format!("[{:06}] HTTP {}", req_id, status.as_u16()));
+        warn!(status = status.as_u16(), "Upstream HTTP {}", status.as_u16());
+        debug!(target: BODY_TARGET, "Upstream error body: {}", body);
         return (StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_GATEWAY), [(header::CONTENT_TYPE, "application/json")], body).into_response();
     }
     
@@ -907,43 +1840,133 @@ async fn openai_handler(State(state): State<Arc<AppState>>, req: Request<Body>)
         let mut headers = HeaderMap::new();
         headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
         headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
-        let state_clone = state.clone();
+        let metrics = state.metrics.clone();
+        let active_stream = metrics.stream_guard();
+        let tags = tags.clone();
+        let shutdown = state.shutdown.clone();
         let stream = async_stream::stream! {
+            let _active_stream = active_stream;
+            let mut first_token_seen = false;
+            let mut finish_reason: Option<String> = None;
+            let mut response_id: Option<String> = None;
+            let mut usage: Option<Value> = None;
+            // Relay whole events only, normalised to `\n` line endings; ids and
+            // keep-alive comments pass through so idle-timeout proxies stay quiet
+            let mut decoder = SseDecoder::with_comments();
+            let mut transport_error = false;
             let mut s = resp.bytes_stream();
-            while let Some(chunk) = s.next().await {
+            loop {
+                let chunk = tokio::select! {
+                    chunk = s.next() => chunk,
+                    _ = shutdown.deadline() => {
+                        warn!(latency_ms = start.elapsed().as_millis() as u64, "stream cut off by shutdown");
+                        yield Ok(Bytes::from(shutdown::OPENAI_CUT_OFF_EVENT));
+                        return;
+                    }
+                };
+                let Some(chunk) = chunk else { break };
                 match chunk {
-                    Ok(b) => yield Ok::<_, std::io::Error>(b),
-                    Err(_) => break,
+                    Ok(b) => {
+                        for event in decoder.feed(&b) {
+                            if let Ok(chunk_data) = serde_json::from_str::<Value>(&event.data) {
+                                if !first_token_seen && delta_has_content(&chunk_data["choices"][0]["delta"]) {
+                                    first_token_seen = true;
+                                    metrics.observe_time_to_first_token(&served_model, start.elapsed().as_secs_f64());
+                                }
+                                if chunk_data["usage"].is_object() {
+                                    let converted = anthropic_usage(&chunk_data["usage"]);
+                                    metrics.record_usage(&served_model, &converted);
+                                    tags.set_usage(&converted);
+                                    usage = Some(chunk_data["usage"].clone());
+                                }
+                                if response_id.is_none() {
+                                    response_id = chunk_data["id"].as_str().map(|s| s.to_string());
+                                }
+                                if let Some(reason) = chunk_data["choices"][0]["finish_reason"].as_str() {
+                                    finish_reason.get_or_insert_with(|| reason.to_string());
+                                }
+                            }
+                            yield Ok::<_, std::io::Error>(Bytes::from(event.encode()));
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Stream error: {}", e);
+                        transport_error = true;
+                        break;
+                    }
                 }
             }
-            state_clone.log(LogLevel::Info, &format!("[{:06}] {} stream {}ms", req_id, path, start.elapsed().as_millis()));
+            // A partial event after a transport error may be truncated mid-line: drop it
+            if !transport_error {
+                if let Some(event) = decoder.finish() {
+                    yield Ok(Bytes::from(event.encode()));
+                }
+            }
+            telemetry::record_response(&upstream_span, &served_model, response_id.as_deref(), finish_reason.as_deref(), usage.as_ref());
+            info!(latency_ms = start.elapsed().as_millis() as u64, "stream completed");
         };
+        let stream = logging::instrument_stream(stream, tracing::info_span!("stream_relay"));
         (StatusCode::OK, headers, Body::from_stream(stream)).into_response()
     } else {
         let body = resp.bytes().await.unwrap_or_default();
-        state.log(LogLevel::Info, &format!("[{:06}] {} {}ms", req_id, path, start.elapsed().as_millis()));
+        if let Ok(resp_json) = serde_json::from_slice::<Value>(&body) {
+            let converted = anthropic_usage(&resp_json["usage"]);
+            state.metrics.record_usage(&served_model, &converted);
+            tags.set_usage(&converted);
+            telemetry::record_response(
+                &upstream_span,
+                &served_model,
+                resp_json["id"].as_str(),
+                resp_json["choices"][0]["finish_reason"].as_str(),
+                resp_json.get("usage"),
+            );
+        }
         (StatusCode::OK, [(header::CONTENT_TYPE, "application/json")], body).into_response()
     }
 }
 
 fn transform_openai(
     body: &[u8],
-    model_map: &std::collections::HashMap<String, String>,
+    model_map: &ModelMapper,
 ) -> (Bytes, bool) {
     if body.is_empty() { return (Bytes::new(), false); }
     let mut data: Value = match serde_json::from_slice(body) { Ok(v) => v, Err(_) => return (Bytes::from(body.to_vec()), false) };
     let is_streaming = data.get("stream").and_then(|s| s.as_bool()).unwrap_or(false);
     if let Some(model) = data.get("model").and_then(|m| m.as_str()) {
-        let mapped = map_model(model, model_map);
+        let mapped = model_map.map(model);
         data["model"] = Value::String(mapped);
     }
     if let Some(mt) = data.get("max_tokens").cloned() { data["max_completion_tokens"] = mt; data.as_object_mut().map(|o| o.remove("max_tokens")); }
-    for key in ["reasoning", "reasoningBudgetTokens", "service_tier", "parallel_tool_calls", "logprobs", "seed"] {
+    for key in ["reasoning", "reasoningBudgetTokens", "service_tier", "logprobs", "seed"] {
         data.as_object_mut().map(|o| o.remove(key));
     }
+    let keep_parallel = data.get("model").and_then(|m| m.as_str()).is_some_and(|m| model_map.supports_parallel_tool_calls(m));
+    if !keep_parallel {
+        data.as_object_mut().map(|o| o.remove("parallel_tool_calls"));
+    }
     (Bytes::from(serde_json::to_vec(&data).unwrap_or_default()), is_streaming)
 }
 
+/// Rewrites the `model` of an already-transformed OpenAI body (used for fallbacks)
+fn replace_model(body: &Bytes, model: &str) -> Bytes {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut data) => {
+            data["model"] = json!(model);
+            Bytes::from(serde_json::to_vec(&data).unwrap_or_default())
+        }
+        Err(_) => body.clone(),
+    }
+}
+
+/// Error body in the OpenAI API shape
+fn openai_error(code: u16, error_type: &str, error_code: Option<&str>, msg: &str) -> Response {
+    (
+        StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        [(header::CONTENT_TYPE, "application/json")],
+        json!({"error": {"message": msg, "type": error_type, "param": null, "code": error_code}}).to_string(),
+    ).into_response()
+}
+
 fn error_response(code: u16, msg: &str) -> Response {
     (StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR), [(header::CONTENT_TYPE, "application/json")], json!({"error": msg}).to_string()).into_response()
 }
@@ -955,3 +1978,536 @@ fn synthetic_complete(is_streaming: bool) -> Response {
         (StatusCode::OK, [(header::CONTENT_TYPE, "application/json")], json!({"choices":[{"message":{"role":"assistant","content":"Done."},"finish_reason":"stop"}]}).to_string()).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_image_blocks() {
+        let cases = [
+            (json!({"type": "image", "source": {"type": "base64", "media_type": "image/jpeg", "data": "AAA"}}), Some("data:image/jpeg;base64,AAA")),
+            (json!({"type": "image", "source": {"type": "base64", "data": "BBB"}}), Some("data:image/png;base64,BBB")),
+            (json!({"type": "image", "source": {"type": "url", "url": "https://example.com/a.png"}}), Some("https://example.com/a.png")),
+            (json!({"type": "image", "source": {"type": "base64"}}), None),
+            (json!({"type": "image", "source": {"type": "file", "file_id": "f"}}), None),
+            (json!({"type": "image"}), None),
+        ];
+        for (block, url) in cases {
+            let expected = url.map(|u| json!({"type": "image_url", "image_url": {"url": u}}));
+            assert_eq!(anthropic_image_to_openai(&block), expected, "{}", block);
+        }
+    }
+
+    #[test]
+    fn splits_tool_result_content() {
+        let image = json!({"type": "image", "source": {"type": "url", "url": "https://example.com/a.png"}});
+        let cases = [
+            (Some(json!("plain")), "plain", 0),
+            (Some(json!([{"type": "text", "text": "a"}, {"type": "text", "text": "b"}])), "a\nb", 0),
+            (Some(json!([{"type": "text", "text": "see"}, image])), "see", 1),
+            (Some(json!([{"type": "document", "id": 1}])), "{\"id\":1,\"type\":\"document\"}", 0),
+            (Some(json!(42)), "42", 0),
+            (None, "", 0),
+        ];
+        for (content, text, images) in cases {
+            let (got_text, got_images) = tool_result_content(content.as_ref());
+            assert_eq!((got_text.as_str(), got_images.len()), (text, images), "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn orders_tool_result_images_after_tool_messages() {
+        let image = json!({"type": "image", "source": {"type": "url", "url": "https://example.com/a.png"}});
+        let body = json!({
+            "model": "claude-4-sonnet",
+            "max_tokens": 100,
+            "messages": [
+                {"role": "user", "content": "screenshot please"},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Taking it."},
+                    {"type": "image", "source": {"type": "url", "url": "https://example.com/b.png"}},
+                    {"type": "tool_use", "id": "t1", "name": "screenshot", "input": {}},
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": [{"type": "text", "text": "captured"}, image]},
+                    {"type": "text", "text": "What do you see?"},
+                ]},
+            ],
+        });
+        let mapper = ModelMapper::new(Default::default(), &[], &[], None, true).unwrap();
+        let (req, _) = anthropic_to_openai(body.to_string().as_bytes(), "claude-4-sonnet", &mapper).unwrap();
+        let messages = req["messages"].as_array().unwrap();
+        let roles: Vec<&str> = messages.iter().map(|m| m["role"].as_str().unwrap()).collect();
+        // The assistant image is dropped (with a warning): OpenAI assistant content is text-only
+        assert_eq!(roles, ["user", "assistant", "assistant", "tool", "user"]);
+        assert_eq!(messages[1]["content"], "Taking it.");
+        assert_eq!(messages[2]["tool_calls"][0]["id"], "t1");
+        assert_eq!(messages[3], json!({"role": "tool", "tool_call_id": "t1", "name": "screenshot", "content": "captured\n[1 image(s) attached below]"}));
+        // The follow-up user message keeps the sibling text first, then the labelled tool-result images
+        assert_eq!(messages[4]["content"], json!([
+            {"type": "text", "text": "What do you see?"},
+            {"type": "text", "text": "Image(s) from tool result t1:"},
+            {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+        ]));
+    }
+
+    #[test]
+    fn points_image_only_tool_results_at_their_images() {
+        let image = |url: &str| json!({"type": "image", "source": {"type": "url", "url": url}});
+        let body = json!({
+            "model": "claude-4-sonnet",
+            "max_tokens": 100,
+            "messages": [
+                {"role": "user", "content": "screenshots please"},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "t1", "name": "screenshot", "input": {}},
+                    {"type": "tool_use", "id": "t2", "name": "screenshot", "input": {}},
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "t1", "content": [image("https://example.com/1.png"), image("https://example.com/2.png")]},
+                    {"type": "tool_result", "tool_use_id": "t2", "content": [image("https://example.com/3.png")]},
+                ]},
+            ],
+        });
+        let mapper = ModelMapper::new(Default::default(), &[], &[], None, true).unwrap();
+        let (req, _) = anthropic_to_openai(body.to_string().as_bytes(), "claude-4-sonnet", &mapper).unwrap();
+        let messages = req["messages"].as_array().unwrap();
+        let tool_contents: Vec<&str> = messages.iter().filter(|m| m["role"] == "tool").map(|m| m["content"].as_str().unwrap()).collect();
+        assert_eq!(tool_contents, ["[2 image(s) attached below]", "[1 image(s) attached below]"]);
+        let url = |u: &str| json!({"type": "image_url", "image_url": {"url": u}});
+        assert_eq!(messages.last().unwrap(), &json!({"role": "user", "content": [
+            {"type": "text", "text": "Image(s) from tool result t1:"},
+            url("https://example.com/1.png"),
+            url("https://example.com/2.png"),
+            {"type": "text", "text": "Image(s) from tool result t2:"},
+            url("https://example.com/3.png"),
+        ]}));
+    }
+
+    #[test]
+    fn maps_thinking_budget() {
+        use Reasoning::*;
+        let cases = [
+            (json!({"type": "enabled", "budget_tokens": 2048}), Some(Thinking), json!({"thinking": {"type": "enabled", "budget_tokens": 2048}})),
+            (json!({"type": "enabled"}), Some(Thinking), json!({"thinking": {"type": "enabled", "budget_tokens": 1024}})),
+            (json!({"type": "enabled", "budget_tokens": 4096}), Some(ReasoningEffort), json!({"reasoning_effort": "low"})),
+            (json!({"type": "enabled", "budget_tokens": 4097}), Some(ReasoningEffort), json!({"reasoning_effort": "medium"})),
+            (json!({"type": "enabled", "budget_tokens": 32000}), Some(ReasoningEffort), json!({"reasoning_effort": "high"})),
+            // No reasoning parameter in the catalog: nothing is sent
+            (json!({"type": "enabled", "budget_tokens": 2048}), None, json!({})),
+            (json!({"type": "disabled"}), Some(Thinking), json!({})),
+        ];
+        for (thinking, reasoning, expected) in cases {
+            let mut req = json!({});
+            apply_thinking(&mut req, &thinking, "model", reasoning);
+            assert_eq!(req, expected, "{} {:?}", thinking, reasoning);
+        }
+    }
+
+    #[test]
+    fn replays_prior_thinking_as_reasoning_content() {
+        let body = json!({
+            "model": "claude-4-sonnet",
+            "max_tokens": 100,
+            "messages": [
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": [
+                    {"type": "thinking", "thinking": "Greet ", "signature": "sig-1"},
+                    {"type": "redacted_thinking", "data": "EmwKAhgB"},
+                    {"type": "thinking", "thinking": "back."},
+                    {"type": "text", "text": "Hello!"},
+                ]},
+                {"role": "user", "content": "again"},
+            ],
+        });
+        let mapper = ModelMapper::new(Default::default(), &[], &[], None, true).unwrap();
+        let (req, streaming) = anthropic_to_openai(body.to_string().as_bytes(), "claude-4-sonnet", &mapper).unwrap();
+        assert!(!streaming);
+        let assistant = &req["messages"][1];
+        assert_eq!(assistant["content"], "Hello!");
+        assert_eq!(assistant["reasoning_content"], "Greet back.");
+        // Neither the signature nor the redacted block goes upstream
+        assert_eq!(assistant.as_object().unwrap().len(), 3, "{}", assistant);
+    }
+
+    #[test]
+    fn converts_reasoning_to_a_thinking_block() {
+        let resp = json!({"choices": [{"message": {"content": "42", "reasoning_content": "6 x 7"}, "finish_reason": "stop"}]});
+        let content = &openai_to_anthropic(&resp, "claude-4-sonnet", "u1")["content"];
+        assert_eq!(content, &json!([{"type": "thinking", "thinking": "6 x 7", "signature": ""}, {"type": "text", "text": "42"}]));
+
+        // Only the fields Cortex returns are read
+        let resp = json!({"choices": [{"message": {"content": "42", "reasoning": "6 x 7"}, "finish_reason": "stop"}]});
+        let content = &openai_to_anthropic(&resp, "claude-4-sonnet", "u1")["content"];
+        assert_eq!(content, &json!([{"type": "text", "text": "42"}]));
+    }
+
+    /// Mock Cortex that answers every chat completion with the given SSE body
+    async fn mock_stream(sse: &'static str) -> String {
+        mock_body(move || Body::from(sse)).await
+    }
+
+    /// Mock Cortex that sends `sse`, then fails the connection mid-body
+    async fn mock_broken_stream(sse: &'static str) -> String {
+        mock_body(move || Body::from_stream(async_stream::stream! {
+            yield Ok(Bytes::from(sse));
+            // Let the headers and first chunk go out before failing
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            yield Err(std::io::Error::other("connection reset"));
+        })).await
+    }
+
+    async fn mock_body(body: impl Fn() -> Body + Clone + Send + Sync + 'static) -> String {
+        let app = Router::new().route("/api/v2/cortex/v1/chat/completions", post(move || async move {
+            ([(header::CONTENT_TYPE, "text/event-stream")], body())
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/api/v2/cortex/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        base_url
+    }
+
+    fn test_app(base_url: &str) -> Router {
+        let text = format!("[proxy]\n[snowflake]\nbase_url = \"{}\"\npat = \"pat\"\n", base_url);
+        let config: Config = toml::from_str(&text).unwrap();
+        build_app(config, Arc::new(Metrics::new()), reload::channel().0, Shutdown::new(std::time::Duration::ZERO)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn streams_reasoning_as_a_thinking_block() {
+        use tower::ServiceExt;
+        let base_url = mock_stream(concat!(
+            "data: {\"id\":\"c1\",\"choices\":[{\"delta\":{\"reasoning_content\":\"Let me \"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"reasoning_content\":\"think.\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Done.\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: [DONE]\n\n",
+        )).await;
+        let body = json!({"model": "claude-4-sonnet", "max_tokens": 100, "stream": true, "messages": [{"role": "user", "content": "hi"}]});
+        let req = Request::post("/v1/messages").header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())).unwrap();
+        let resp = test_app(&base_url).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let events: Vec<Value> = String::from_utf8_lossy(&bytes).lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let summary: Vec<String> = events.iter().map(|e| match e["type"].as_str().unwrap() {
+            "content_block_start" => format!("start {} {}", e["index"], e["content_block"]["type"].as_str().unwrap()),
+            "content_block_delta" => format!("delta {} {}", e["index"], e["delta"]["type"].as_str().unwrap()),
+            "content_block_stop" => format!("stop {}", e["index"]),
+            other => other.to_string(),
+        }).collect();
+        assert_eq!(summary, [
+            "message_start",
+            "start 0 thinking", "delta 0 thinking_delta", "delta 0 thinking_delta", "stop 0",
+            "start 1 text", "delta 1 text_delta", "stop 1",
+            "message_delta", "message_stop",
+        ]);
+        assert_eq!(events[1]["content_block"]["signature"], "");
+        assert_eq!(events[2]["delta"]["thinking"], "Let me ");
+    }
+
+    #[tokio::test]
+    async fn drops_a_partial_event_after_a_transport_error() {
+        use tower::ServiceExt;
+        let base_url = mock_broken_stream(concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"trunc\"}}]}",
+        )).await;
+        let body = json!({"model": "claude-4-sonnet", "max_tokens": 100, "stream": true, "messages": [{"role": "user", "content": "hi"}]});
+        let req = Request::post("/v1/messages").header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())).unwrap();
+        let resp = test_app(&base_url).oneshot(req).await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let text: Vec<String> = String::from_utf8_lossy(&bytes).lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+            .filter_map(|e| e["delta"]["text"].as_str().map(str::to_string))
+            .collect();
+        assert_eq!(text, ["Hi"]);
+    }
+
+    #[test]
+    fn maps_tool_choice() {
+        assert_eq!(anthropic_tool_choice_to_openai(&json!({"type": "auto"})), Some(json!("auto")));
+        assert_eq!(anthropic_tool_choice_to_openai(&json!({"type": "any"})), Some(json!("required")));
+        assert_eq!(anthropic_tool_choice_to_openai(&json!({"type": "none"})), Some(json!("none")));
+        assert_eq!(
+            anthropic_tool_choice_to_openai(&json!({"type": "tool", "name": "get_weather"})),
+            Some(json!({"type": "function", "function": {"name": "get_weather"}})),
+        );
+        // A tool choice without a name, an unknown type or a bare string is dropped
+        assert_eq!(anthropic_tool_choice_to_openai(&json!({"type": "tool"})), None);
+        assert_eq!(anthropic_tool_choice_to_openai(&json!({"type": "sometimes"})), None);
+        assert_eq!(anthropic_tool_choice_to_openai(&json!("auto")), None);
+    }
+
+    #[test]
+    fn keeps_parallel_tool_calls_for_catalog_models_that_accept_it() {
+        let catalog = [
+            ModelInfo { id: "claude-4-sonnet".to_string(), parallel_tool_calls: true, ..Default::default() },
+            ModelInfo { id: "llama3.1-70b".to_string(), ..Default::default() },
+        ];
+        let mapper = ModelMapper::new(Default::default(), &[], &catalog, None, true).unwrap();
+        for (model, kept) in [("claude-4-sonnet", true), ("llama3.1-70b", false), ("not-in-catalog", false)] {
+            let body = json!({"model": model, "parallel_tool_calls": false});
+            let (out, _) = transform_openai(body.to_string().as_bytes(), &mapper);
+            let out: Value = serde_json::from_slice(&out).unwrap();
+            assert_eq!(out.get("parallel_tool_calls").is_some(), kept, "{}", model);
+        }
+    }
+
+    #[test]
+    fn requests_stream_usage_only_from_catalog_models_that_accept_it() {
+        let catalog = [
+            ModelInfo { id: "claude-4-sonnet".to_string(), stream_usage: true, ..Default::default() },
+            ModelInfo { id: "llama3.1-70b".to_string(), ..Default::default() },
+        ];
+        let mapper = ModelMapper::new(Default::default(), &[], &catalog, None, true).unwrap();
+        let cases = [("claude-4-sonnet", true, true), ("claude-4-sonnet", false, false), ("llama3.1-70b", true, false), ("not-in-catalog", true, false)];
+        for (model, stream, requested) in cases {
+            let mut req = json!({"model": model, "stream": stream});
+            request_stream_usage(&mut req, model, &mapper);
+            assert_eq!(req.get("stream_options").is_some(), requested, "{} stream={}", model, stream);
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_real_usage_in_message_delta() {
+        use tower::ServiceExt;
+        let base_url = mock_stream(concat!(
+            "data: {\"id\":\"c1\",\"choices\":[{\"delta\":{\"content\":\"Hi\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":1234,\"completion_tokens\":5}}\n\n",
+            "data: [DONE]\n\n",
+        )).await;
+        let body = json!({"model": "claude-4-sonnet", "max_tokens": 100, "stream": true, "messages": [{"role": "user", "content": "hi"}]});
+        let req = Request::post("/v1/messages").header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())).unwrap();
+        let resp = test_app(&base_url).oneshot(req).await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let events: Vec<Value> = String::from_utf8_lossy(&bytes).lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        // message_start can only carry the estimate; message_delta replaces it with Cortex's count
+        let start = &events[0]["message"]["usage"];
+        assert_ne!(start["input_tokens"], 1234);
+        let delta = events.iter().find(|e| e["type"] == "message_delta").unwrap();
+        assert_eq!(delta["usage"], json!({"input_tokens": 1234, "output_tokens": 5}));
+    }
+
+    #[tokio::test]
+    async fn estimates_output_when_cortex_reports_no_usage() {
+        use tower::ServiceExt;
+        let base_url = mock_stream(concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hello, world\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"id\":\"t1\",\"function\":{\"name\":\"ls\",\"arguments\":\"{}\"}}]},\"finish_reason\":\"tool_calls\"}]}\n\n",
+            "data: [DONE]\n\n",
+        )).await;
+        let body = json!({"model": "claude-4-sonnet", "max_tokens": 100, "stream": true, "messages": [{"role": "user", "content": "hi"}]});
+        let req = Request::post("/v1/messages").header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())).unwrap();
+        let resp = test_app(&base_url).oneshot(req).await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let events: Vec<Value> = String::from_utf8_lossy(&bytes).lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let delta = events.iter().find(|e| e["type"] == "message_delta").unwrap();
+        // 12 text + 2 name + 2 argument characters, ~4 per token
+        assert_eq!(delta["usage"]["output_tokens"], 4);
+        assert_eq!(delta["usage"]["input_tokens"], events[0]["message"]["usage"]["input_tokens"]);
+    }
+
+    #[test]
+    fn estimates_input_tokens() {
+        assert_eq!(estimate_input_tokens(&json!({"messages": []})), 0);
+        // 4 per message plus ~4 characters per token, rounded up
+        let text = json!({"messages": [{"role": "user", "content": "abcdefghi"}]});
+        assert_eq!(estimate_input_tokens(&text), 4 + 3);
+        let image = json!({"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}});
+        let parts = json!({"messages": [{"role": "user", "content": [{"type": "text", "text": "abcd"}, image]}]});
+        assert_eq!(estimate_input_tokens(&parts), 4 + IMAGE_TOKEN_ESTIMATE + 1);
+        let reasoning = json!({"messages": [{"role": "assistant", "content": "abcd", "reasoning_content": "abcd"}]});
+        assert_eq!(estimate_input_tokens(&reasoning), 4 + 2);
+        assert_eq!(estimate_input_tokens(&json!({"messages": [], "tools": [{"a": 1}]})), 3);
+    }
+
+    /// Anthropic request exercising system blocks, tools, a tool round trip and sampling options
+    fn full_anthropic_request() -> Value {
+        json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 512,
+            "system": [{"type": "text", "text": "You are terse."}, {"type": "text", "text": "Use tools."}],
+            "temperature": 0.2,
+            "stop_sequences": ["END"],
+            "tools": [{"name": "get_weather", "description": "Weather for a city", "input_schema": {"type": "object", "properties": {"city": {"type": "string"}}}}],
+            "tool_choice": {"type": "auto", "disable_parallel_tool_use": true},
+            "messages": [
+                {"role": "user", "content": [{"type": "text", "text": "Weather in Oslo?"}]},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Checking."},
+                    {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Oslo"}},
+                ]},
+                {"role": "user", "content": [{"type": "tool_result", "tool_use_id": "toolu_1", "content": "4°C"}]},
+            ],
+        })
+    }
+
+    #[test]
+    fn converts_a_full_request() {
+        let mapper = ModelMapper::new(Default::default(), &[], &default_model_catalog(), None, true).unwrap();
+        let (req, streaming) = anthropic_to_openai(full_anthropic_request().to_string().as_bytes(), "claude-4-sonnet", &mapper).unwrap();
+        assert!(!streaming);
+        // Assistant text and tool calls go out as separate messages, as Cortex expects
+        assert_eq!(req, json!({
+            "model": "claude-sonnet-4-5",
+            "max_completion_tokens": 512,
+            "stream": false,
+            "temperature": 0.2,
+            "stop": ["END"],
+            "messages": [
+                {"role": "system", "content": "You are terse.\nUse tools."},
+                {"role": "user", "content": "Weather in Oslo?"},
+                {"role": "assistant", "content": "Checking."},
+                {"role": "assistant", "content": null, "tool_calls": [
+                    {"id": "toolu_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":\"Oslo\"}"}},
+                ]},
+                {"role": "tool", "tool_call_id": "toolu_1", "name": "get_weather", "content": "4°C"},
+            ],
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "description": "Weather for a city",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}},
+            }}],
+            "tool_choice": "auto",
+            "parallel_tool_calls": false,
+        }));
+    }
+
+    #[test]
+    fn converts_usage_excluding_cached_tokens() {
+        let cases = [
+            (json!({"prompt_tokens": 100, "completion_tokens": 20}), json!({"input_tokens": 100, "output_tokens": 20})),
+            (
+                json!({"prompt_tokens": 100, "completion_tokens": 20, "prompt_tokens_details": {"cached_tokens": 60}}),
+                json!({"input_tokens": 40, "output_tokens": 20, "cache_read_input_tokens": 60, "cache_creation_input_tokens": 0}),
+            ),
+            (
+                json!({"prompt_tokens": 100, "completion_tokens": 5, "cache_read_input_tokens": 30, "cache_creation_input_tokens": 50,
+                       "prompt_tokens_details": {"cached_tokens": 99}}),
+                json!({"input_tokens": 20, "output_tokens": 5, "cache_read_input_tokens": 30, "cache_creation_input_tokens": 50}),
+            ),
+            // Inconsistent counts don't underflow
+            (json!({"prompt_tokens": 10, "cache_read_input_tokens": 30}), json!({"input_tokens": 0, "output_tokens": 0, "cache_read_input_tokens": 30, "cache_creation_input_tokens": 0})),
+            (Value::Null, json!({"input_tokens": 0, "output_tokens": 0})),
+        ];
+        for (usage, expected) in cases {
+            assert_eq!(anthropic_usage(&usage), expected, "{}", usage);
+        }
+    }
+
+    #[test]
+    fn validates_client_request_ids() {
+        let id = |value: &str| client_request_id(&HeaderMap::from_iter([(header::HeaderName::from_static("x-request-id"), HeaderValue::from_str(value).unwrap())]));
+        assert_eq!(id(" trace-1.A_b "), Some("trace-1.A_b".to_string()));
+        assert_eq!(id(&"a".repeat(64)), Some("a".repeat(64)));
+        assert_eq!(id(&"a".repeat(65)), None);
+        assert_eq!(id("a b"), None);
+        assert_eq!(id("id;drop"), None);
+        assert_eq!(id(""), None);
+        assert_eq!(client_request_id(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn lists_models_in_the_client_format() {
+        use tower::ServiceExt;
+        let app = test_app("http://127.0.0.1:9/api/v2/cortex/v1");
+        let list = |anthropic: bool| {
+            let mut req = Request::get("/v1/models");
+            if anthropic {
+                req = req.header("anthropic-version", "2023-06-01");
+            }
+            let app = app.clone();
+            async move {
+                let resp = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+                serde_json::from_slice::<Value>(&axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap()).unwrap()
+            }
+        };
+        let anthropic = list(true).await;
+        let first = &anthropic["data"][0];
+        assert_eq!(first["id"], "claude-4-sonnet", "default_model comes first");
+        assert_eq!(first["type"], "model");
+        assert!(first.get("created_at").is_none());
+        assert_eq!(anthropic["first_id"], "claude-4-sonnet");
+
+        let openai = list(false).await;
+        let ids: Vec<&str> = openai["data"].as_array().unwrap().iter().map(|m| m["id"].as_str().unwrap()).collect();
+        assert!(ids.contains(&"llama3.1-70b") && ids.contains(&"mistral-large2"), "{:?}", ids);
+        assert_eq!(openai["object"], "list");
+    }
+
+    #[tokio::test]
+    async fn counts_tokens_of_the_converted_request() {
+        use tower::ServiceExt;
+        let app = test_app("http://127.0.0.1:9/api/v2/cortex/v1");
+        let count = |body: String| {
+            let req = Request::post("/v1/messages/count_tokens").header(header::CONTENT_TYPE, "application/json").body(Body::from(body)).unwrap();
+            let app = app.clone();
+            async move {
+                let resp = app.oneshot(req).await.unwrap();
+                let status = resp.status().as_u16();
+                (status, serde_json::from_slice::<Value>(&axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap()).unwrap())
+            }
+        };
+        // Five messages plus tools, counted after conversion; Cortex is never called
+        let (status, body) = count(full_anthropic_request().to_string()).await;
+        assert_eq!((status, body), (200, json!({"input_tokens": 100})));
+        let (status, body) = count("{not json".to_string()).await;
+        assert_eq!(status, 400);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+
+    /// Status of `GET path` from `peer`, optionally with an API key
+    async fn admin_status(path: &str, config: &str, peer: Peer, key: Option<&str>) -> u16 {
+        use tower::ServiceExt;
+        let ledger = std::env::temp_dir().join(format!("cortex-proxy-admin-{}.jsonl", std::process::id()));
+        let text = format!(
+            "[proxy]\n[snowflake]\nbase_url = \"http://127.0.0.1:9/api/v2/cortex/v1\"\npat = \"pat\"\n[usage]\nenabled = true\npath = {:?}\n{}",
+            ledger, config,
+        );
+        let config: Config = toml::from_str(&text).unwrap();
+        let app = build_app(config, Arc::new(Metrics::new()), reload::channel().0, Shutdown::new(std::time::Duration::ZERO)).unwrap();
+        let mut req = Request::get(path).body(Body::empty()).unwrap();
+        req.extensions_mut().insert(peer);
+        if let Some(key) = key {
+            req.headers_mut().insert("x-api-key", HeaderValue::from_str(key).unwrap());
+        }
+        app.oneshot(req).await.unwrap().status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_need_a_local_client_or_an_admin_key() {
+        let peer = |addr: &str| Peer(Some(addr.parse().unwrap()));
+        let remote = peer("203.0.113.7:50000");
+        let metrics = "[metrics]\nenabled = true\n";
+        for path in ["/admin/usage", "/metrics"] {
+            assert_eq!(admin_status(path, metrics, remote, None).await, 403, "{}", path);
+            assert_eq!(admin_status(path, metrics, peer("127.0.0.1:50000"), None).await, 200, "{}", path);
+        }
+        assert_eq!(admin_status("/admin/usage", "", peer("[::1]:50000"), None).await, 200);
+        assert_eq!(admin_status("/admin/usage", "", peer("[::ffff:127.0.0.1]:50000"), None).await, 200);
+        assert_eq!(admin_status("/admin/usage", "", Peer(None), None).await, 200, "Unix socket");
+        // Opting out leaves /metrics open to remote scrapers
+        assert_eq!(admin_status("/metrics", "[metrics]\nenabled = true\nrequire_admin = false\n", remote, None).await, 200);
+
+        let auth = format!(
+            "{}[auth]\nenabled = true\n[[auth.keys]]\nname = \"ops\"\nsha256 = \"{}\"\nadmin = true\n[[auth.keys]]\nname = \"dev\"\nsha256 = \"{}\"\n",
+            metrics, auth::hash_key("ops-key"), auth::hash_key("dev-key"),
+        );
+        for path in ["/admin/usage", "/metrics"] {
+            assert_eq!(admin_status(path, &auth, remote, Some("ops-key")).await, 200, "{}", path);
+            assert_eq!(admin_status(path, &auth, remote, Some("dev-key")).await, 403, "{}", path);
+            assert_eq!(admin_status(path, &auth, peer("127.0.0.1:50000"), None).await, 401, "{}: loopback still needs a key", path);
+        }
+        let _ = std::fs::remove_file(std::env::temp_dir().join(format!("cortex-proxy-admin-{}.jsonl", std::process::id())));
+    }
+}
@@ -0,0 +1,226 @@
+//! Prometheus metrics, served on `[metrics] path` when `[metrics] enabled = true`
+//!
+//! Handlers fill in the model and streaming flag through `RequestTags`; the
+//! request middleware counts the request once the response status is known.
+//! Model labels are limited to the models the config knows about (see
+//! `set_known_models`); any other name a client passes through counts as "other",
+//! so clients can't create unbounded series.
+
+use prometheus_client::encoding::{text::encode, EncodeLabelSet};
+use prometheus_client::metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::{exponential_buckets, Histogram}};
+use prometheus_client::registry::Registry;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use crate::usage::RequestTags;
+
+/// `[metrics]` section
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Guard the endpoint like `/admin/*`: an admin key with `[auth]`, local clients without
+    #[serde(default = "default_require_admin")]
+    pub require_admin: bool,
+}
+
+fn default_path() -> String { "/metrics".to_string() }
+fn default_require_admin() -> bool { true }
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self { enabled: false, path: default_path(), require_admin: default_require_admin() }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    route: String,
+    model: String,
+    status: u16,
+    stream: bool,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ModelLabels {
+    model: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TokenLabels {
+    model: String,
+    /// "input", "output", "cache_read" or "cache_write"
+    kind: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RetryLabels {
+    model: String,
+    reason: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ApiLabels {
+    /// "anthropic" or "openai"
+    api: &'static str,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+/// Seconds, 50ms .. ~100s
+fn latency_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.05, 2.0, 12))
+}
+
+pub struct Metrics {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    upstream_latency: HistogramFamily<ModelLabels>,
+    time_to_first_token: HistogramFamily<ModelLabels>,
+    tokens: Family<TokenLabels, Counter>,
+    retries: Family<RetryLabels, Counter>,
+    synthetic_completions: Family<ApiLabels, Counter>,
+    active_streams: Gauge,
+    /// Model names used as labels as-is
+    known_models: RwLock<HashSet<String>>,
+}
+
+/// Label for models outside `known_models`
+const OTHER_MODEL: &str = "other";
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("cortex_proxy");
+        let requests = Family::<RequestLabels, Counter>::default();
+        let upstream_latency = HistogramFamily::<ModelLabels>::new_with_constructor(latency_histogram);
+        let time_to_first_token = HistogramFamily::<ModelLabels>::new_with_constructor(latency_histogram);
+        let tokens = Family::<TokenLabels, Counter>::default();
+        let retries = Family::<RetryLabels, Counter>::default();
+        let synthetic_completions = Family::<ApiLabels, Counter>::default();
+        let active_streams = Gauge::default();
+        registry.register("requests", "Client requests by route, model, status and streaming flag", requests.clone());
+        registry.register("upstream_latency_seconds", "Time until Cortex returned response headers, per attempt", upstream_latency.clone());
+        registry.register("time_to_first_token_seconds", "Time from request start to the first streamed content", time_to_first_token.clone());
+        registry.register("tokens", "Tokens reported by Cortex usage", tokens.clone());
+        registry.register("upstream_retries", "Upstream attempts that were retried", retries.clone());
+        registry.register("synthetic_completions", "Synthetic \"Done.\" completions returned instead of an upstream error", synthetic_completions.clone());
+        registry.register("active_streams", "Streaming responses currently being relayed", active_streams.clone());
+        Self {
+            registry, requests, upstream_latency, time_to_first_token, tokens, retries, synthetic_completions, active_streams,
+            known_models: RwLock::default(),
+        }
+    }
+
+    /// Replaces the models labelled by name; set from the catalog and model mapping on every (re)load
+    pub fn set_known_models(&self, models: impl IntoIterator<Item = String>) {
+        *self.known_models.write().unwrap() = models.into_iter().collect();
+    }
+
+    fn model_label(&self, model: &str) -> String {
+        match model.is_empty() || self.known_models.read().unwrap().contains(model) {
+            true => model.to_string(),
+            false => OTHER_MODEL.to_string(),
+        }
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // Writing to a String cannot fail
+        let _ = encode(&mut out, &self.registry);
+        out
+    }
+
+    pub fn record_request(&self, route: &str, tags: &RequestTags, status: u16) {
+        let (model, stream) = tags.labels();
+        let model = self.model_label(&model);
+        self.requests.get_or_create(&RequestLabels { route: route.to_string(), model, status, stream }).inc();
+    }
+
+    pub fn observe_upstream_latency(&self, model: &str, secs: f64) {
+        self.upstream_latency.get_or_create(&ModelLabels { model: self.model_label(model) }).observe(secs);
+    }
+
+    pub fn observe_time_to_first_token(&self, model: &str, secs: f64) {
+        self.time_to_first_token.get_or_create(&ModelLabels { model: self.model_label(model) }).observe(secs);
+    }
+
+    /// Counts tokens from an Anthropic-shaped usage object (see `anthropic_usage`)
+    pub fn record_usage(&self, model: &str, usage: &Value) {
+        for (kind, field) in [
+            ("input", "input_tokens"),
+            ("output", "output_tokens"),
+            ("cache_read", "cache_read_input_tokens"),
+            ("cache_write", "cache_creation_input_tokens"),
+        ] {
+            if let Some(n) = usage[field].as_u64().filter(|n| *n > 0) {
+                self.tokens.get_or_create(&TokenLabels { model: self.model_label(model), kind }).inc_by(n);
+            }
+        }
+    }
+
+    pub fn record_retry(&self, model: &str, reason: &str) {
+        self.retries.get_or_create(&RetryLabels { model: self.model_label(model), reason: reason.to_string() }).inc();
+    }
+
+    pub fn record_synthetic_completion(&self, api: &'static str) {
+        self.synthetic_completions.get_or_create(&ApiLabels { api }).inc();
+    }
+
+    /// Counts an active stream until the returned guard is dropped, which also
+    /// covers clients that disconnect mid-stream
+    pub fn stream_guard(&self) -> StreamGuard {
+        self.active_streams.inc();
+        StreamGuard(self.active_streams.clone())
+    }
+}
+
+pub struct StreamGuard(Gauge);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn encodes_known_models_and_buckets_the_rest() {
+        let metrics = Metrics::new();
+        metrics.set_known_models(["claude-4-sonnet".to_string()]);
+        let tags = RequestTags::new("id", "/v1/messages", "anonymous", None);
+        tags.set_model("claude-4-sonnet");
+        tags.set_stream(true);
+        metrics.record_request("/v1/messages", &tags, 200);
+        tags.set_model("made-up-model-1234");
+        metrics.record_request("/v1/messages", &tags, 200);
+        metrics.record_usage("claude-4-sonnet", &json!({"input_tokens": 12, "output_tokens": 3, "cache_read_input_tokens": 0}));
+        metrics.record_usage("another-made-up-model", &json!({"input_tokens": 5}));
+        metrics.record_retry("made-up-model-1234", "429");
+        let _stream = metrics.stream_guard();
+
+        let out = metrics.render();
+        for line in [
+            "cortex_proxy_requests_total{route=\"/v1/messages\",model=\"claude-4-sonnet\",status=\"200\",stream=\"true\"} 1",
+            "cortex_proxy_requests_total{route=\"/v1/messages\",model=\"other\",status=\"200\",stream=\"true\"} 1",
+            "cortex_proxy_tokens_total{model=\"claude-4-sonnet\",kind=\"input\"} 12",
+            "cortex_proxy_tokens_total{model=\"claude-4-sonnet\",kind=\"output\"} 3",
+            "cortex_proxy_tokens_total{model=\"other\",kind=\"input\"} 5",
+            "cortex_proxy_upstream_retries_total{model=\"other\",reason=\"429\"} 1",
+            "cortex_proxy_active_streams 1",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}\n{}", line, out);
+        }
+        assert!(!out.contains("made-up"), "{}", out);
+        assert!(!out.contains("cache_read"), "zero counts are skipped");
+    }
+}
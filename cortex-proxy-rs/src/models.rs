@@ -0,0 +1,274 @@
+//! Model catalog and client model name -> Cortex model mapping
+//!
+//! Resolution order for a client model name:
+//!   1. exact `model_map` alias
+//!   2. a Cortex model id from the catalog (passed through unchanged)
+//!   3. `[[model_rules]]` glob/regex patterns, highest priority first, then
+//!      the built-in rules for dated Claude names (unless `builtin_model_rules = false`)
+//!   4. `snowflake.fallback_model`, if configured
+//!   5. the name itself (pass-through)
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Catalog entry advertised by /v1/models
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub context_window: Option<u64>,
+    #[serde(default)]
+    pub max_output_tokens: Option<u64>,
+    /// Accepts the OpenAI `parallel_tool_calls` parameter; dropped from requests otherwise
+    #[serde(default)]
+    pub parallel_tool_calls: bool,
+    /// Accepts `stream_options: {include_usage: true}`, so streams end with real token counts
+    #[serde(default)]
+    pub stream_usage: bool,
+    /// How the model takes extended thinking; unset drops a client's thinking request
+    #[serde(default)]
+    pub reasoning: Option<Reasoning>,
+}
+
+/// Cortex request parameter that enables reasoning
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Reasoning {
+    /// Anthropic-style `thinking: {type: "enabled", budget_tokens}`
+    Thinking,
+    /// OpenAI-style `reasoning_effort: "low" | "medium" | "high"`
+    ReasoningEffort,
+}
+
+pub fn default_model_catalog() -> Vec<ModelInfo> {
+    use Reasoning::{ReasoningEffort, Thinking};
+    [
+        ("claude-opus-4-5", "Claude Opus 4.5", 200_000, 64_000, true, Some(Thinking)),
+        ("claude-sonnet-4-5", "Claude Sonnet 4.5", 200_000, 64_000, true, Some(Thinking)),
+        ("claude-4-opus", "Claude Opus 4", 200_000, 32_000, true, Some(Thinking)),
+        ("claude-4-sonnet", "Claude Sonnet 4", 200_000, 64_000, true, Some(Thinking)),
+        ("claude-haiku-4-5", "Claude Haiku 4.5", 200_000, 64_000, true, Some(Thinking)),
+        ("claude-3-5-sonnet", "Claude 3.5 Sonnet", 200_000, 8_192, true, None),
+        ("openai-gpt-5", "GPT-5", 272_000, 128_000, true, Some(ReasoningEffort)),
+        ("openai-gpt-4.1", "GPT-4.1", 1_047_576, 32_768, true, None),
+        ("llama3.3-70b", "Llama 3.3 70B", 128_000, 8_192, false, None),
+        ("llama3.1-405b", "Llama 3.1 405B", 128_000, 8_192, false, None),
+        ("llama3.1-70b", "Llama 3.1 70B", 128_000, 8_192, false, None),
+        ("llama3.1-8b", "Llama 3.1 8B", 128_000, 8_192, false, None),
+        ("mistral-large2", "Mistral Large 2", 128_000, 8_192, false, None),
+        ("deepseek-r1", "DeepSeek R1", 32_768, 8_192, false, None),
+    ].into_iter().map(|(id, name, ctx, out, openai_params, reasoning)| ModelInfo {
+        id: id.to_string(),
+        display_name: Some(name.to_string()),
+        context_window: Some(ctx),
+        max_output_tokens: Some(out),
+        parallel_tool_calls: openai_params,
+        stream_usage: openai_params,
+        reasoning,
+    }).collect()
+}
+
+/// Pattern rule from `[[model_rules]]`: exactly one of `pattern` (glob) or `regex`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ModelRule {
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub regex: Option<String>,
+    /// Target Cortex model; regex rules may reference capture groups (`$1`)
+    pub target: String,
+    #[serde(default)]
+    pub priority: i64,
+}
+
+/// Built-in rules, tried after the configured `[[model_rules]]`: map the dated
+/// Anthropic model names Claude Code sends onto their Cortex equivalents
+pub fn default_model_rules() -> Vec<ModelRule> {
+    [
+        ("*opus-4-5*", "claude-opus-4-5", 40),
+        ("*4-5-opus*", "claude-opus-4-5", 40),
+        ("*sonnet-4-5*", "claude-sonnet-4-5", 40),
+        ("*4-5-sonnet*", "claude-sonnet-4-5", 40),
+        ("*4-opus*", "claude-4-opus", 30),
+        ("*opus-4*", "claude-4-opus", 30),
+        ("*sonnet-4*", "claude-4-sonnet", 30),
+        ("*3-7*sonnet*", "claude-4-sonnet", 10),
+        ("*haiku*", "claude-haiku-4-5", 20),
+        ("*3-5*sonnet*", "claude-3-5-sonnet", 10),
+        ("*sonnet*3-5*", "claude-3-5-sonnet", 10),
+    ].into_iter().map(|(pattern, target, priority)| ModelRule {
+        pattern: Some(pattern.to_string()),
+        regex: None,
+        target: target.to_string(),
+        priority,
+    }).collect()
+}
+
+#[derive(Clone)]
+struct CompiledRule {
+    regex: Regex,
+    target: String,
+    priority: i64,
+}
+
+/// Resolves client model names to Cortex model names
+#[derive(Clone)]
+pub struct ModelMapper {
+    pub aliases: HashMap<String, String>,
+    catalog: Vec<ModelInfo>,
+    rules: Vec<CompiledRule>,
+    fallback: Option<String>,
+}
+
+/// Translates a glob (`*`, `?`) into an anchored, case-insensitive regex
+pub fn glob_to_regex(glob: &str) -> String {
+    let escaped = regex::escape(glob).replace("\\*", ".*").replace("\\?", ".");
+    format!("(?i)^{}$", escaped)
+}
+
+impl ModelMapper {
+    /// Configured `rules` come first (highest priority first), then the built-in ones if `builtin_rules`
+    pub fn new(
+        aliases: HashMap<String, String>,
+        rules: &[ModelRule],
+        catalog: &[ModelInfo],
+        fallback: Option<String>,
+        builtin_rules: bool,
+    ) -> Result<Self, String> {
+        let mut compiled = compile_rules(rules)?;
+        if builtin_rules {
+            compiled.extend(compile_rules(&default_model_rules())?);
+        }
+        Ok(Self {
+            aliases,
+            catalog: catalog.to_vec(),
+            rules: compiled,
+            fallback,
+        })
+    }
+
+    /// Catalog entry for a Cortex model id
+    pub fn info(&self, model: &str) -> Option<&ModelInfo> {
+        self.catalog.iter().find(|m| m.id == model)
+    }
+
+    /// Whether the Cortex model accepts the OpenAI `parallel_tool_calls` parameter
+    pub fn supports_parallel_tool_calls(&self, model: &str) -> bool {
+        self.info(model).is_some_and(|m| m.parallel_tool_calls)
+    }
+
+    /// Whether the Cortex model accepts `stream_options.include_usage`
+    pub fn supports_stream_usage(&self, model: &str) -> bool {
+        self.info(model).is_some_and(|m| m.stream_usage)
+    }
+
+    pub fn map(&self, model: &str) -> String {
+        if let Some(mapped) = self.aliases.get(model) {
+            return mapped.clone();
+        }
+        if self.info(model).is_some() {
+            return model.to_string();
+        }
+        for rule in &self.rules {
+            if let Some(caps) = rule.regex.captures(model) {
+                let mut target = String::new();
+                caps.expand(&rule.target, &mut target);
+                return target;
+            }
+        }
+        self.fallback.clone().unwrap_or_else(|| model.to_string())
+    }
+}
+
+/// Compiles `rules` sorted by priority
+fn compile_rules(rules: &[ModelRule]) -> Result<Vec<CompiledRule>, String> {
+    let mut compiled = Vec::with_capacity(rules.len());
+    for rule in rules {
+        let source = match (&rule.pattern, &rule.regex) {
+            (Some(glob), None) => glob_to_regex(glob),
+            (None, Some(re)) => re.clone(),
+            _ => return Err(format!("model rule for '{}' needs exactly one of `pattern` or `regex`", rule.target)),
+        };
+        let regex = Regex::new(&source).map_err(|e| format!("invalid model rule '{}': {}", source, e))?;
+        compiled.push(CompiledRule { regex, target: rule.target.clone(), priority: rule.priority });
+    }
+    // Stable sort keeps config order among equal priorities
+    compiled.sort_by_key(|r| std::cmp::Reverse(r.priority));
+    Ok(compiled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: Option<&str>, regex: Option<&str>, target: &str, priority: i64) -> ModelRule {
+        ModelRule { pattern: pattern.map(str::to_string), regex: regex.map(str::to_string), target: target.to_string(), priority }
+    }
+
+    #[test]
+    fn resolves_in_order() {
+        let aliases = HashMap::from([("fast".to_string(), "claude-haiku-4-5".to_string()), ("claude-4-opus".to_string(), "claude-opus-4-5".to_string())]);
+        let rules = [
+            rule(Some("*haiku*"), None, "claude-haiku-4-5", 20),
+            rule(None, Some(r"^(llama3\.1-\d+b)-instruct$"), "$1", 10),
+            // Equal priority: the earlier rule wins
+            rule(Some("*haiku-3*"), None, "claude-3-haiku", 20),
+        ];
+        let mapper = ModelMapper::new(aliases, &rules, &default_model_catalog(), Some("mistral-large2".to_string()), false).unwrap();
+        let cases = [
+            // Alias beats catalog id and rules
+            ("fast", "claude-haiku-4-5"),
+            ("claude-4-opus", "claude-opus-4-5"),
+            // Catalog ids pass through even when a rule matches
+            ("claude-3-5-sonnet", "claude-3-5-sonnet"),
+            // Higher priority first, case-insensitive globs
+            ("claude-3-HAIKU-20240307", "claude-haiku-4-5"),
+            ("llama3.1-70b-instruct", "llama3.1-70b"),
+            // Without built-in rules, unmatched names (Claude or not) go to the fallback
+            ("claude-sonnet-4-20250514", "mistral-large2"),
+            ("gpt-4o", "mistral-large2"),
+        ];
+        for (model, expected) in cases {
+            assert_eq!(mapper.map(model), expected, "{}", model);
+        }
+        let pass_through = ModelMapper::new(HashMap::new(), &[], &[], None, true).unwrap();
+        assert_eq!(pass_through.map("gpt-4o"), "gpt-4o");
+        assert_eq!(pass_through.map("claude-sonnet-9"), "claude-sonnet-9");
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        assert!(ModelMapper::new(HashMap::new(), &[rule(Some("a*"), Some("^a"), "x", 0)], &[], None, true).is_err());
+        assert!(ModelMapper::new(HashMap::new(), &[rule(None, None, "x", 0)], &[], None, true).is_err());
+        assert!(ModelMapper::new(HashMap::new(), &[rule(None, Some("("), "x", 0)], &[], None, true).is_err());
+    }
+
+    #[test]
+    fn configured_rules_take_precedence_over_the_defaults() {
+        let config: crate::Config = toml::from_str("[proxy]\n[snowflake]\n").unwrap();
+        let mapper = ModelMapper::new(HashMap::new(), &config.model_rules, &config.model_catalog, Some("claude-4-sonnet".to_string()), true).unwrap();
+        assert_eq!(mapper.map("claude-sonnet-4-5-20250929"), "claude-sonnet-4-5");
+        assert_eq!(mapper.map("claude-3-5-haiku-20241022"), "claude-haiku-4-5");
+        // Claude Code's default and 3.7 Sonnet have no direct Cortex equivalent
+        assert_eq!(mapper.map("claude-sonnet-4-20250514"), "claude-4-sonnet");
+        assert_eq!(mapper.map("claude-3-7-sonnet-20250219"), "claude-4-sonnet");
+        // No built-in catch-all: unknown Claude names reach the fallback like any other name
+        assert_eq!(mapper.map("claude-sonnet-9"), "claude-4-sonnet");
+
+        let config: crate::Config = toml::from_str("[proxy]\n[snowflake]\n[[model_rules]]\npattern = \"*haiku*\"\ntarget = \"claude-3-5-sonnet\"\n").unwrap();
+        let mapper = ModelMapper::new(HashMap::new(), &config.model_rules, &config.model_catalog, None, true).unwrap();
+        // A low-priority configured rule still beats a higher-priority built-in one
+        assert_eq!(mapper.map("claude-3-5-haiku-20241022"), "claude-3-5-sonnet");
+        assert_eq!(mapper.map("claude-sonnet-4-5-20250929"), "claude-sonnet-4-5");
+
+        let config: crate::Config = toml::from_str("[proxy]\n[snowflake]\nbuiltin_model_rules = false\nfallback_model = \"claude-4-sonnet\"\n").unwrap();
+        let mapper = ModelMapper::new(HashMap::new(), &config.model_rules, &config.model_catalog, config.snowflake.fallback_model.clone(), config.snowflake.builtin_model_rules).unwrap();
+        // With the built-in rules off, the fallback applies to dated Claude names too
+        assert_eq!(mapper.map("claude-3-5-haiku-20241022"), "claude-4-sonnet");
+    }
+}
@@ -0,0 +1,95 @@
+//! Config hot reload
+//!
+//! A reload is triggered by a change to the config file (polled), SIGHUP or
+//! `POST /admin/reload`. The new config builds a complete router and state that
+//! replace the current ones for new requests only; in-flight requests and
+//! streams keep the `AppState` they started with. A config that fails to load
+//! is logged (or returned to the admin caller) and the running one stays.
+
+use axum::Router;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+/// How often the config file's modification time is checked
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The router currently serving requests
+#[derive(Clone)]
+pub struct LiveApp(Arc<RwLock<Router>>);
+
+impl LiveApp {
+    pub fn new(app: Router) -> Self {
+        Self(Arc::new(RwLock::new(app)))
+    }
+
+    pub fn current(&self) -> Router {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, app: Router) {
+        *self.0.write().unwrap() = app;
+    }
+}
+
+/// Admin-triggered reload; the reply carries the outcome
+pub type ReloadRequest = oneshot::Sender<Result<(), String>>;
+
+pub fn channel() -> (mpsc::UnboundedSender<ReloadRequest>, mpsc::UnboundedReceiver<ReloadRequest>) {
+    mpsc::unbounded_channel()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Waits for reload triggers and swaps in the router returned by `rebuild`.
+/// `rebuild` reads files and may run secret commands, so it runs on the
+/// blocking pool rather than an async worker.
+pub async fn run<F>(live: LiveApp, config_path: PathBuf, watch: bool, mut requests: mpsc::UnboundedReceiver<ReloadRequest>, rebuild: F)
+where
+    F: Fn(&str) -> Result<Router, String> + Send + Sync + 'static,
+{
+    let rebuild = Arc::new(rebuild);
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+    let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+    let mut last_modified = modified(&config_path);
+    loop {
+        #[cfg(unix)]
+        let sighup = async {
+            match hangup.as_mut() {
+                Some(signal) => { signal.recv().await; }
+                None => std::future::pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let sighup = std::future::pending::<()>();
+
+        let (trigger, reply) = tokio::select! {
+            _ = sighup => ("SIGHUP", None),
+            Some(reply) = requests.recv() => ("admin endpoint", Some(reply)),
+            _ = ticker.tick(), if watch => {
+                let current = modified(&config_path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                ("file change", None)
+            }
+        };
+        let rebuild = rebuild.clone();
+        let result = tokio::task::spawn_blocking(move || rebuild(trigger)).await
+            .unwrap_or_else(|e| Err(format!("reload panicked: {}", e)))
+            .map(|app| live.replace(app));
+        match &result {
+            Ok(()) => info!("🔄 Config reloaded ({})", trigger),
+            Err(e) => error!("Config reload ({}) failed, keeping the current config: {}", trigger, e),
+        }
+        if let Some(reply) = reply {
+            let _ = reply.send(result);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Named Snowflake upstreams (accounts) and the rules that choose between them
+//!
+//! Each request is matched against `[[routes]]` in order; the first rule whose
+//! conditions all hold names the candidate upstreams (no match: every upstream).
+//! Candidates that don't serve the model or are ejected are skipped, and the
+//! rest are picked by weight. An upstream is ejected for `eject_secs` after
+//! `max_failures` consecutive connection errors or 5xx responses.
+
+use axum::http::HeaderMap;
+use rand::Rng;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::credentials::{Credentials, SnowflakeAuth};
+use crate::models::glob_to_regex;
+
+/// `[[upstreams]]` entry
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub name: String,
+    /// Cortex API URL, e.g. https://<account>.snowflakecomputing.com/api/v2/cortex/v1
+    pub base_url: String,
+    #[serde(default)]
+    pub pat: Option<String>,
+    /// Key-pair auth instead of `pat`
+    #[serde(default)]
+    pub auth: Option<SnowflakeAuth>,
+    /// Cortex models (globs) this account serves; empty means any
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Share of traffic relative to the other candidates
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// `[[routes]]` entry; every condition that is set must match
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    /// Glob on the Cortex model, e.g. "claude-opus-*"
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Client name (the `[auth]` key name, or `x-cortex-client`)
+    #[serde(default)]
+    pub client: Option<String>,
+    /// Request headers that must carry these exact values
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Upstream names to choose from
+    pub upstreams: Vec<String>,
+}
+
+/// `[upstream_health]` section
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Consecutive failures before an upstream is ejected
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    #[serde(default = "default_eject_secs")]
+    pub eject_secs: u64,
+}
+
+fn default_weight() -> u32 { 1 }
+fn default_max_failures() -> u32 { 3 }
+fn default_eject_secs() -> u64 { 30 }
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self { max_failures: default_max_failures(), eject_secs: default_eject_secs() }
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    consecutive_failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+pub struct Upstream {
+    pub name: String,
+    pub base_url: String,
+    pub credentials: Arc<Credentials>,
+    models: Vec<Regex>,
+    weight: u32,
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    fn serves(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|re| re.is_match(model))
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.health.lock().unwrap().ejected_until.is_none_or(|until| Instant::now() >= until)
+    }
+}
+
+struct CompiledRoute {
+    model: Option<Regex>,
+    client: Option<String>,
+    headers: Vec<(String, String)>,
+    upstreams: Vec<usize>,
+}
+
+impl CompiledRoute {
+    fn matches(&self, model: &str, client: &str, headers: &HeaderMap) -> bool {
+        self.model.as_ref().is_none_or(|re| re.is_match(model))
+            && self.client.as_deref().is_none_or(|c| c == client)
+            && self.headers.iter().all(|(name, value)| {
+                headers.get(name).and_then(|v| v.to_str().ok()) == Some(value.as_str())
+            })
+    }
+}
+
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+    routes: Vec<CompiledRoute>,
+    health: HealthConfig,
+}
+
+impl Upstreams {
+    pub fn new(configs: &[UpstreamConfig], routes: &[RouteRule], health: HealthConfig) -> Result<Self, String> {
+        if configs.is_empty() {
+            return Err("no upstreams configured: set [snowflake] base_url and pat, or add [[upstreams]]".to_string());
+        }
+        let mut upstreams = Vec::with_capacity(configs.len());
+        for config in configs {
+            if upstreams.iter().any(|u: &Upstream| u.name == config.name) {
+                return Err(format!("duplicate upstream name '{}'", config.name));
+            }
+            let models = config.models.iter()
+                .map(|glob| Regex::new(&glob_to_regex(glob)).map_err(|e| format!("upstream '{}' model '{}': {}", config.name, glob, e)))
+                .collect::<Result<_, _>>()?;
+            let credentials = Credentials::shared(&config.base_url, config.pat.as_deref(), config.auth.as_ref())
+                .map_err(|e| format!("upstream '{}': {}", config.name, e))?;
+            upstreams.push(Upstream {
+                name: config.name.clone(),
+                base_url: config.base_url.trim_end_matches('/').to_string(),
+                credentials,
+                models,
+                weight: config.weight,
+                health: Mutex::new(Health::default()),
+            });
+        }
+        let index = |name: &str| {
+            upstreams.iter().position(|u| u.name == name).ok_or_else(|| format!("route refers to unknown upstream '{}'", name))
+        };
+        let mut compiled = Vec::with_capacity(routes.len());
+        for route in routes {
+            if route.upstreams.is_empty() {
+                return Err("every [[routes]] entry needs at least one upstream".to_string());
+            }
+            compiled.push(CompiledRoute {
+                model: route.model.as_deref()
+                    .map(|glob| Regex::new(&glob_to_regex(glob)).map_err(|e| format!("route model '{}': {}", glob, e)))
+                    .transpose()?,
+                client: route.client.clone(),
+                headers: route.headers.iter().map(|(k, v)| (k.to_ascii_lowercase(), v.clone())).collect(),
+                upstreams: route.upstreams.iter().map(|name| index(name)).collect::<Result<_, _>>()?,
+            });
+        }
+        Ok(Self { upstreams, routes: compiled, health })
+    }
+
+    pub fn len(&self) -> usize {
+        self.upstreams.len()
+    }
+
+    /// Chooses the upstream for one attempt. Upstreams in `tried` are avoided
+    /// while others remain; ejected ones are only used when nothing else is left.
+    pub fn pick(&self, model: &str, client: &str, headers: &HeaderMap, tried: &[usize]) -> usize {
+        let candidates: Vec<usize> = match self.routes.iter().find(|r| r.matches(model, client, headers)) {
+            Some(route) => route.upstreams.clone(),
+            None => (0..self.upstreams.len()).collect(),
+        };
+        let serving: Vec<usize> = candidates.iter().copied().filter(|&i| self.upstreams[i].serves(model)).collect();
+        let pool = if serving.is_empty() { candidates } else { serving };
+        let healthy: Vec<usize> = pool.iter().copied().filter(|&i| self.upstreams[i].is_healthy()).collect();
+        let pool = if healthy.is_empty() { pool } else { healthy };
+        let fresh: Vec<usize> = pool.iter().copied().filter(|i| !tried.contains(i)).collect();
+        let pool = if fresh.is_empty() { pool } else { fresh };
+        self.weighted(&pool)
+    }
+
+    fn weighted(&self, pool: &[usize]) -> usize {
+        let total: u32 = pool.iter().map(|&i| self.upstreams[i].weight).sum();
+        if total == 0 {
+            return pool[0];
+        }
+        let mut n = rand::rng().random_range(0..total);
+        for &i in pool {
+            let weight = self.upstreams[i].weight;
+            if n < weight {
+                return i;
+            }
+            n -= weight;
+        }
+        pool[0]
+    }
+
+    pub fn get(&self, index: usize) -> &Upstream {
+        &self.upstreams[index]
+    }
+
+    /// Records an attempt's outcome; `failed` means a connection error or 5xx
+    pub fn record(&self, index: usize, failed: bool) {
+        let upstream = &self.upstreams[index];
+        let mut health = upstream.health.lock().unwrap();
+        if !failed {
+            if health.ejected_until.take().is_some() {
+                info!("Upstream {} recovered", upstream.name);
+            }
+            health.consecutive_failures = 0;
+            return;
+        }
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.health.max_failures.max(1) && self.upstreams.len() > 1 {
+            health.ejected_until = Some(Instant::now() + Duration::from_secs(self.health.eject_secs));
+            health.consecutive_failures = 0;
+            warn!("Upstream {} ejected for {}s after repeated failures", upstream.name, self.health.eject_secs);
+        }
+    }
+
+    /// (name, healthy) for every upstream, for /health
+    pub fn status(&self) -> Vec<(&str, bool)> {
+        self.upstreams.iter().map(|u| (u.name.as_str(), u.is_healthy())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn upstream(name: &str, models: &[&str], weight: u32) -> UpstreamConfig {
+        UpstreamConfig {
+            name: name.to_string(),
+            base_url: format!("https://{}.example/api/v2/cortex/v1", name),
+            pat: Some("pat".to_string()),
+            auth: None,
+            models: models.iter().map(|m| m.to_string()).collect(),
+            weight,
+        }
+    }
+
+    fn route(model: Option<&str>, client: Option<&str>, headers: &[(&str, &str)], upstreams: &[&str]) -> RouteRule {
+        RouteRule {
+            model: model.map(str::to_string),
+            client: client.map(str::to_string),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            upstreams: upstreams.iter().map(|u| u.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn routes_by_header_client_and_model() {
+        let pool = Upstreams::new(
+            &[upstream("us", &[], 1), upstream("eu", &[], 1), upstream("sandbox", &[], 1)],
+            &[
+                route(None, None, &[("X-Cortex-Env", "sandbox")], &["sandbox"]),
+                route(None, Some("team-eu"), &[], &["eu"]),
+                route(Some("claude-opus-*"), None, &[], &["us"]),
+            ],
+            HealthConfig::default(),
+        ).unwrap();
+        let none = HeaderMap::new();
+        let mut sandbox = HeaderMap::new();
+        sandbox.insert("x-cortex-env", HeaderValue::from_static("sandbox"));
+        assert_eq!(pool.get(pool.pick("claude-opus-4-5", "team-eu", &sandbox, &[])).name, "sandbox");
+        assert_eq!(pool.get(pool.pick("claude-opus-4-5", "team-eu", &none, &[])).name, "eu");
+        assert_eq!(pool.get(pool.pick("claude-opus-4-5", "anonymous", &none, &[])).name, "us");
+    }
+
+    #[test]
+    fn respects_models_weights_and_ejection() {
+        let pool = Upstreams::new(
+            &[upstream("a", &["claude-*"], 1), upstream("b", &[], 0), upstream("c", &[], 1)],
+            &[],
+            HealthConfig { max_failures: 2, eject_secs: 60 },
+        ).unwrap();
+        let headers = HeaderMap::new();
+        // "a" only serves claude-* and "b" has no weight, so "c" takes everything else
+        for _ in 0..20 {
+            assert_ne!(pool.get(pool.pick("llama3.1-70b", "anonymous", &headers, &[])).name, "a");
+            assert_ne!(pool.get(pool.pick("llama3.1-70b", "anonymous", &headers, &[])).name, "b", "weight 0 is never picked");
+        }
+        pool.record(2, true);
+        assert!(pool.status()[2].1);
+        pool.record(2, true);
+        assert!(!pool.status()[2].1);
+        // With "c" ejected the zero-weight upstream is all that's left
+        assert_eq!(pool.get(pool.pick("llama3.1-70b", "anonymous", &headers, &[])).name, "b");
+        pool.record(2, false);
+        assert!(pool.status()[2].1);
+    }
+
+    #[test]
+    fn rejects_unknown_upstream_in_route() {
+        let err = Upstreams::new(&[upstream("a", &[], 1)], &[route(None, None, &[], &["b"])], HealthConfig::default());
+        assert!(err.is_err());
+    }
+}
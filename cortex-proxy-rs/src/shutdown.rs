@@ -0,0 +1,153 @@
+//! Graceful shutdown
+//!
+//! SIGTERM, Ctrl-C or, with `serve --shutdown-on-eof`, stdin closing start a
+//! drain: the listener stops accepting, idle keep-alive connections close and
+//! in-flight requests get up to `[proxy] shutdown_timeout_secs` to finish. At
+//! the deadline, streams still open end with an error event in their API's
+//! format and the process exits. A second signal skips straight to the deadline.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Phase {
+    Running,
+    Draining,
+    Deadline,
+}
+
+/// Shared shutdown state; clones observe the same phase
+#[derive(Clone)]
+pub struct Shutdown {
+    phase: Arc<watch::Sender<Phase>>,
+    /// How long in-flight requests may run once draining starts
+    pub timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(timeout: Duration) -> Self {
+        Self { phase: Arc::new(watch::Sender::new(Phase::Running)), timeout }
+    }
+
+    fn advance(&self, phase: Phase) -> bool {
+        self.phase.send_if_modified(|current| {
+            let later = *current < phase;
+            if later {
+                *current = phase;
+            }
+            later
+        })
+    }
+
+    /// Stops accepting connections; returns false if a shutdown was already under way
+    pub fn drain(&self) -> bool {
+        self.advance(Phase::Draining)
+    }
+
+    /// Ends whatever is still running
+    pub fn expire(&self) {
+        if self.advance(Phase::Deadline) {
+            warn!("Shutdown deadline reached; ending open streams");
+        }
+    }
+
+    async fn reached(&self, phase: Phase) {
+        let mut rx = self.phase.subscribe();
+        let _ = rx.wait_for(|current| *current >= phase).await;
+    }
+
+    /// Resolves once a shutdown starts
+    pub async fn draining(&self) {
+        self.reached(Phase::Draining).await
+    }
+
+    /// Resolves once in-flight requests are out of time
+    pub async fn deadline(&self) {
+        self.reached(Phase::Deadline).await
+    }
+}
+
+/// Sent to Anthropic streams still open at the deadline, in place of their remaining events
+pub const ANTHROPIC_CUT_OFF_EVENT: &str = "event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Proxy is shutting down; the response was cut off\"}}\n\n";
+
+/// Sent to OpenAI streams still open at the deadline: an error chunk, then the end of the stream
+pub const OPENAI_CUT_OFF_EVENT: &str = "data: {\"error\":{\"message\":\"Proxy is shutting down; the response was cut off\",\"type\":\"server_error\",\"param\":null,\"code\":null}}\n\ndata: [DONE]\n\n";
+
+/// Drains on the first SIGTERM or Ctrl-C and expires on the second
+pub async fn watch_signals(shutdown: Shutdown) {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).ok();
+    loop {
+        #[cfg(unix)]
+        let sigterm = async {
+            match terminate.as_mut() {
+                Some(signal) => { signal.recv().await; }
+                None => std::future::pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let sigterm = std::future::pending::<()>();
+
+        let name = tokio::select! {
+            _ = sigterm => "SIGTERM",
+            _ = tokio::signal::ctrl_c() => "Ctrl-C",
+        };
+        if shutdown.drain() {
+            info!("{} received; finishing in-flight requests (up to {}s, signal again to stop now)", name, shutdown.timeout.as_secs());
+        } else {
+            shutdown.expire();
+        }
+    }
+}
+
+/// Drains once stdin reaches end of file, i.e. when a supervising parent closes
+/// the pipe. Reads on a plain thread so a pending read never holds up exit.
+pub fn watch_stdin(shutdown: Shutdown) {
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut std::io::stdin().lock(), &mut std::io::sink());
+        if shutdown.drain() {
+            info!("stdin closed; finishing in-flight requests (up to {}s)", shutdown.timeout.as_secs());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn phases_only_move_forward() {
+        let shutdown = Shutdown::new(Duration::from_secs(30));
+        let deadline = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.deadline().await }
+        });
+        assert!(shutdown.drain());
+        assert!(!shutdown.drain());
+        shutdown.draining().await;
+        tokio::task::yield_now().await;
+        assert!(!deadline.is_finished());
+        shutdown.expire();
+        deadline.await.unwrap();
+        // Later waiters see the phase already reached
+        shutdown.draining().await;
+    }
+
+    #[test]
+    fn cut_off_events_are_well_formed() {
+        let events = crate::sse::SseDecoder::new().feed(ANTHROPIC_CUT_OFF_EVENT.as_bytes());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.as_deref(), Some("error"));
+        let error: serde_json::Value = serde_json::from_str(&events[0].data).unwrap();
+        assert_eq!(error["error"]["type"], "overloaded_error");
+
+        let events = crate::sse::SseDecoder::new().feed(OPENAI_CUT_OFF_EVENT.as_bytes());
+        let data: Vec<&str> = events.iter().map(|e| e.data.as_str()).collect();
+        assert_eq!(data.len(), 2);
+        let error: serde_json::Value = serde_json::from_str(data[0]).unwrap();
+        assert_eq!(error["error"]["type"], "server_error");
+        assert_eq!(data[1], "[DONE]");
+    }
+}
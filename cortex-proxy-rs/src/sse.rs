@@ -0,0 +1,269 @@
+//! Incremental Server-Sent Events decoder
+//!
+//! Works on raw bytes so multi-byte UTF-8 characters split across TCP chunks are
+//! only decoded once their line is complete. Follows the WHATWG event-stream
+//! rules: `\n`, `\r\n` and `\r` line endings, multi-line `data:` fields joined
+//! with `\n`, `event:`/`id:` fields, comment lines and an optional leading BOM.
+//!
+//! One deliberate leniency: the spec discards an event still pending at EOF,
+//! but `finish` dispatches it, so an upstream that closes right after its last
+//! `data:` line (e.g. `data: [DONE]` with no blank line) isn't cut short. Call it
+//! only on a clean EOF; after a transport error the pending bytes may be truncated.
+
+/// A dispatched event, or a comment line when the decoder keeps them
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+    /// The last event id seen on the stream, which the spec carries over to later events
+    pub id: Option<String>,
+    /// Text after the `:` of a comment line (keep-alives); `data` is empty then
+    pub comment: Option<String>,
+}
+
+impl SseEvent {
+    /// Re-encodes the event with `\n` line endings
+    pub fn encode(&self) -> String {
+        if let Some(comment) = &self.comment {
+            return format!(":{}\n\n", comment);
+        }
+        let mut out = String::with_capacity(self.data.len() + 16);
+        if let Some(id) = &self.id {
+            out.push_str("id: ");
+            out.push_str(id);
+            out.push('\n');
+        }
+        if let Some(event) = &self.event {
+            out.push_str("event: ");
+            out.push_str(event);
+            out.push('\n');
+        }
+        for line in self.data.split('\n') {
+            out.push_str("data: ");
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push('\n');
+        out
+    }
+}
+
+#[derive(Default)]
+pub struct SseDecoder {
+    buf: Vec<u8>,
+    /// The previous chunk ended on `\r`; a leading `\n` belongs to that line ending
+    skip_lf: bool,
+    started: bool,
+    event: Option<String>,
+    data: String,
+    has_data: bool,
+    id: Option<String>,
+    /// Return comment lines as events instead of skipping them
+    keep_comments: bool,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A decoder that also returns comment lines, for relaying a stream as is
+    pub fn with_comments() -> Self {
+        Self { keep_comments: true, ..Self::default() }
+    }
+
+    /// Feeds a chunk and returns every event completed by it
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut chunk = chunk;
+        if self.skip_lf && !chunk.is_empty() {
+            if chunk[0] == b'\n' {
+                chunk = &chunk[1..];
+            }
+            self.skip_lf = false;
+        }
+        self.buf.extend_from_slice(chunk);
+        if !self.started && self.buf.len() >= 3 {
+            if self.buf.starts_with(b"\xEF\xBB\xBF") {
+                self.buf.drain(..3);
+            }
+            self.started = true;
+        }
+
+        let mut events = vec![];
+        let mut consumed = 0;
+        while let Some(offset) = self.buf[consumed..].iter().position(|&b| b == b'\n' || b == b'\r') {
+            let end = consumed + offset;
+            let line = String::from_utf8_lossy(&self.buf[consumed..end]).into_owned();
+            let mut next = end + 1;
+            if self.buf[end] == b'\r' {
+                match self.buf.get(next) {
+                    Some(b'\n') => next += 1,
+                    Some(_) => {}
+                    None => self.skip_lf = true,
+                }
+            }
+            consumed = next;
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+        }
+        // Compact once per chunk rather than once per event
+        if consumed > 0 {
+            self.started = true;
+            self.buf.drain(..consumed);
+        }
+        events
+    }
+
+    /// Flushes a final event the upstream closed without a trailing blank line;
+    /// only for a clean EOF (see the module docs)
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.buf.is_empty() {
+            let line = String::from_utf8_lossy(&std::mem::take(&mut self.buf)).into_owned();
+            // A trailing line is a complete field even without its terminator
+            let _ = self.process_line(&line);
+        }
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if let Some(comment) = line.strip_prefix(':') {
+            return self.keep_comments.then(|| SseEvent { comment: Some(comment.to_string()), ..SseEvent::default() });
+        }
+        let (field, value) = match line.find(':') {
+            Some(idx) => {
+                let value = &line[idx + 1..];
+                (&line[..idx], value.strip_prefix(' ').unwrap_or(value))
+            }
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => {
+                if self.has_data {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value);
+                self.has_data = true;
+            }
+            "id" if !value.contains('\0') => self.id = Some(value.to_string()),
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if !self.has_data {
+            return None;
+        }
+        self.has_data = false;
+        Some(SseEvent {
+            event,
+            data: std::mem::take(&mut self.data),
+            id: self.id.clone(),
+            comment: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events: Vec<SseEvent> = chunks.iter().flat_map(|c| decoder.feed(c)).collect();
+        events.extend(decoder.finish());
+        events
+    }
+
+    fn data(events: &[SseEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.data.as_str()).collect()
+    }
+
+    #[test]
+    fn splits_events_on_blank_lines() {
+        let events = feed_all(&[b"data: one\n\ndata: two\n\n"]);
+        assert_eq!(data(&events), ["one", "two"]);
+    }
+
+    #[test]
+    fn reassembles_utf8_split_across_chunks() {
+        let payload = "data: héllo 🌍 日本語\n\n".as_bytes();
+        // Split inside every multi-byte character
+        for split in 1..payload.len() {
+            let events = feed_all(&[&payload[..split], &payload[split..]]);
+            assert_eq!(data(&events), ["héllo 🌍 日本語"], "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn handles_crlf_and_cr_line_endings() {
+        let events = feed_all(&[b"data: a\r\n\r\ndata: b\r\rdata: c\n\n"]);
+        assert_eq!(data(&events), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn handles_crlf_split_between_chunks() {
+        let events = feed_all(&[b"data: a\r", b"\n\r", b"\ndata: b\r\n\r\n"]);
+        assert_eq!(data(&events), ["a", "b"]);
+    }
+
+    #[test]
+    fn joins_multi_line_data_and_reads_event_names() {
+        let events = feed_all(&[b"event: message_start\ndata: {\"a\":\ndata: 1}\nid: 7\n\n"]);
+        assert_eq!(events, [SseEvent {
+            event: Some("message_start".to_string()),
+            data: "{\"a\":\n1}".to_string(),
+            id: Some("7".to_string()),
+            comment: None,
+        }]);
+    }
+
+    #[test]
+    fn ignores_comments_and_fieldless_events() {
+        let events = feed_all(&[b": keep-alive\n\nevent: ping\n\ndata:x\n\n"]);
+        assert_eq!(data(&events), ["x"]);
+        assert_eq!(events[0].event, None);
+    }
+
+    #[test]
+    fn strips_leading_bom_even_when_split() {
+        let events = feed_all(&[b"\xEF", b"\xBB\xBFdata: x\n\n"]);
+        assert_eq!(data(&events), ["x"]);
+    }
+
+    #[test]
+    fn keeps_comments_when_asked() {
+        let mut decoder = SseDecoder::with_comments();
+        let events = decoder.feed(b": keep-alive\n\ndata: x\n: mid\n\n");
+        let encoded: Vec<String> = events.iter().map(SseEvent::encode).collect();
+        // A comment inside an event is relayed before the event it interrupted
+        assert_eq!(encoded, [": keep-alive\n\n", ": mid\n\n", "data: x\n\n"]);
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let event = SseEvent { event: Some("delta".to_string()), data: "a\nb".to_string(), id: None, comment: None };
+        assert_eq!(event.encode(), "event: delta\ndata: a\ndata: b\n\n");
+        assert_eq!(feed_all(&[event.encode().as_bytes()]), [event]);
+
+        let event = SseEvent { id: Some("42".to_string()), data: "x".to_string(), ..SseEvent::default() };
+        assert_eq!(event.encode(), "id: 42\ndata: x\n\n");
+        assert_eq!(feed_all(&[event.encode().as_bytes()]), [event]);
+        // The last id carries over, so relaying it on later events keeps the client's last-event-id
+        let events = feed_all(&[b"id: 1\ndata: a\n\ndata: b\n\n"]);
+        let relayed: String = events.iter().map(SseEvent::encode).collect();
+        assert_eq!(feed_all(&[relayed.as_bytes()]), events);
+    }
+
+    #[test]
+    fn flushes_unterminated_final_event() {
+        let events = feed_all(&[b"data: [DONE]"]);
+        assert_eq!(data(&events), ["[DONE]"]);
+    }
+}
@@ -0,0 +1,246 @@
+//! OpenTelemetry trace export over OTLP/HTTP
+//!
+//! The `tracing` spans the proxy already creates are exported as OTel spans:
+//! the request span (kind server), `convert`, the upstream `{operation} {model}` call
+//! (kind client, GenAI semantic conventions) and `stream_relay`. An incoming
+//! W3C `traceparent` header makes the request span a child of the caller's trace.
+
+use axum::http::HeaderMap;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{Protocol, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use tracing::{field::Empty, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::{LevelFilter, Targets}, registry::LookupSpan, Layer};
+
+use crate::logging::BODY_TARGET;
+
+/// `[otel]` section; export is disabled unless `endpoint` is set
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OtelConfig {
+    /// Collector base URL, e.g. "http://localhost:4318" (`/v1/traces` is appended)
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// "http/protobuf" or "http/json"
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Extra headers sent with every export, e.g. collector credentials
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+fn default_protocol() -> String { "http/protobuf".to_string() }
+fn default_service_name() -> String { "cortex-proxy".to_string() }
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            protocol: default_protocol(),
+            service_name: default_service_name(),
+            headers: HashMap::new(),
+        }
+    }
+}
+
+/// Builds the batch-exporting tracer provider, or `None` when export is disabled
+pub fn tracer_provider(config: &OtelConfig) -> Result<Option<SdkTracerProvider>, String> {
+    let Some(endpoint) = config.endpoint.as_deref().filter(|e| !e.is_empty()) else {
+        return Ok(None);
+    };
+    let protocol = match config.protocol.as_str() {
+        "http/protobuf" => Protocol::HttpBinary,
+        "http/json" => Protocol::HttpJson,
+        other => return Err(format!("invalid otel protocol '{}': expected \"http/protobuf\" or \"http/json\"", other)),
+    };
+    let endpoint = endpoint.trim_end_matches('/');
+    let endpoint = if endpoint.ends_with("/v1/traces") { endpoint.to_string() } else { format!("{}/v1/traces", endpoint) };
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_protocol(protocol)
+        .with_endpoint(endpoint)
+        .with_headers(config.headers.clone())
+        .build()
+        .map_err(|e| format!("otel exporter: {}", e))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(config.service_name.clone()).build())
+        .build();
+    Ok(Some(provider))
+}
+
+/// Exports the proxy's own spans, independent of `log_level` and never bodies
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let targets = Targets::new()
+        .with_target("cortex_proxy", LevelFilter::INFO)
+        .with_target(BODY_TARGET, LevelFilter::OFF);
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("cortex-proxy"))
+        .with_filter(targets)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Continues the caller's trace when the request carries `traceparent`
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    if headers.contains_key("traceparent") {
+        let cx = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+        let _ = span.set_parent(cx);
+    }
+}
+
+/// Client span for the upstream Cortex call, named `{operation} {model}` per the
+/// GenAI conventions. It stays open until the response body has been fully read.
+pub fn genai_span(operation: &str, model: &str, openai_req: &Value) -> Span {
+    let span = tracing::info_span!(
+        "upstream",
+        otel.name = %format!("{} {}", operation, model),
+        otel.kind = "client",
+        otel.status_code = Empty,
+        gen_ai.operation.name = %operation,
+        gen_ai.provider.name = "snowflake_cortex",
+        gen_ai.request.model = %model,
+        gen_ai.request.max_tokens = Empty,
+        gen_ai.request.temperature = Empty,
+        gen_ai.request.top_p = Empty,
+        gen_ai.response.model = Empty,
+        gen_ai.response.id = Empty,
+        gen_ai.response.finish_reasons = Empty,
+        gen_ai.usage.input_tokens = Empty,
+        gen_ai.usage.output_tokens = Empty,
+        error.type = Empty,
+    );
+    // Integers are recorded as i64: tracing-opentelemetry exports u64 as a string
+    let max_tokens = openai_req.get("max_completion_tokens").or_else(|| openai_req.get("max_tokens"));
+    if let Some(n) = max_tokens.and_then(|v| v.as_i64()) {
+        span.record("gen_ai.request.max_tokens", n);
+    }
+    if let Some(t) = openai_req.get("temperature").and_then(|v| v.as_f64()) {
+        span.record("gen_ai.request.temperature", t);
+    }
+    if let Some(p) = openai_req.get("top_p").and_then(|v| v.as_f64()) {
+        span.record("gen_ai.request.top_p", p);
+    }
+    span
+}
+
+/// Marks the span failed with `error_type` (an HTTP status or error class)
+pub fn record_error(span: &Span, error_type: &str) {
+    span.record("otel.status_code", "ERROR");
+    span.record("error.type", error_type);
+}
+
+/// Records the served model, response id, finish reason and an OpenAI usage object
+pub fn record_response(span: &Span, model: &str, id: Option<&str>, finish_reason: Option<&str>, usage: Option<&Value>) {
+    span.record("gen_ai.response.model", model);
+    if let Some(id) = id {
+        span.record("gen_ai.response.id", id);
+    }
+    // tracing fields are scalars, so the finish reasons array is exported as one string
+    if let Some(reason) = finish_reason {
+        span.record("gen_ai.response.finish_reasons", reason);
+    }
+    if let Some(usage) = usage {
+        if let Some(n) = usage.get("prompt_tokens").and_then(|v| v.as_i64()) {
+            span.record("gen_ai.usage.input_tokens", n);
+        }
+        if let Some(n) = usage.get("completion_tokens").and_then(|v| v.as_i64()) {
+            span.record("gen_ai.usage.output_tokens", n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Minimal OTLP/HTTP collector: answers every POST with 200 and hands back (path, body)
+    fn collector() -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() { break; }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+                let _ = tx.send((path, body));
+            }
+        });
+        (format!("http://{}", addr), rx)
+    }
+
+    fn attribute<'a>(span: &'a Value, key: &str) -> &'a Value {
+        let attrs = span["attributes"].as_array().unwrap();
+        &attrs.iter().find(|a| a["key"] == key).unwrap_or_else(|| panic!("missing {}", key))["value"]
+    }
+
+    #[test]
+    fn exports_upstream_span_with_genai_attributes() {
+        let (endpoint, rx) = collector();
+        let config = OtelConfig { endpoint: Some(endpoint), protocol: "http/json".to_string(), ..Default::default() };
+        let provider = tracer_provider(&config).unwrap().unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = genai_span("chat", "claude-4-sonnet", &json!({"max_tokens": 64, "temperature": 0.5}));
+            let usage = json!({"prompt_tokens": 11, "completion_tokens": 3});
+            record_response(&span, "claude-4-sonnet", Some("msg_1"), Some("stop"), Some(&usage));
+        });
+        provider.force_flush().unwrap();
+
+        let (path, body) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(path, "/v1/traces");
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["name"], "chat claude-4-sonnet");
+        assert_eq!(span["kind"], 3); // SPAN_KIND_CLIENT
+        assert_eq!(attribute(span, "gen_ai.operation.name")["stringValue"], "chat");
+        assert_eq!(attribute(span, "gen_ai.request.model")["stringValue"], "claude-4-sonnet");
+        assert_eq!(attribute(span, "gen_ai.request.max_tokens")["intValue"], "64");
+        assert_eq!(attribute(span, "gen_ai.response.finish_reasons")["stringValue"], "stop");
+        assert_eq!(attribute(span, "gen_ai.usage.input_tokens")["intValue"], "11");
+        assert_eq!(attribute(span, "gen_ai.usage.output_tokens")["intValue"], "3");
+    }
+}
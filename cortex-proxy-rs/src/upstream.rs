@@ -0,0 +1,479 @@
+//! Upstream Cortex calls with retry/backoff and model fallback chains
+//!
+//! Retries happen before any response bytes reach the client: a request is only
+//! re-sent when the connection failed or Cortex answered with a retryable status,
+//! so streaming responses are never replayed mid-stream. Once retries on a model
+//! are exhausted, the same request moves on to that model's `[model_fallbacks]`.
+//! Every attempt picks an upstream account (see `routing`), preferring one that
+//! hasn't failed yet, and carries that account's credentials.
+
+use axum::http::HeaderMap;
+use rand::Rng;
+use reqwest::{RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::warn;
+
+use crate::routing::Upstream;
+use crate::usage::RequestTags;
+use crate::AppState;
+
+pub enum UpstreamError {
+    Request(reqwest::Error),
+    /// No token could be obtained for the chosen upstream
+    Credentials(String),
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Request(e) => write!(f, "{}", e),
+            UpstreamError::Credentials(e) => write!(f, "credentials: {}", e),
+        }
+    }
+}
+
+/// What `[[routes]]` match on, and where the serving upstream is recorded
+pub struct RouteContext<'a> {
+    pub headers: &'a HeaderMap,
+    pub tags: &'a RequestTags,
+}
+
+/// `[proxy.retry]` policy
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Total attempts including the first one (1 disables retries)
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Randomise each delay between 50% and 100% of the backoff
+    #[serde(default = "default_true")]
+    pub jitter: bool,
+    #[serde(default = "default_retry_statuses")]
+    pub retry_statuses: Vec<u16>,
+    /// Wait as long as `Retry-After` asks (giving up if it exceeds `max_delay_ms`)
+    #[serde(default = "default_true")]
+    pub respect_retry_after: bool,
+}
+
+fn default_max_attempts() -> u32 { 3 }
+fn default_base_delay_ms() -> u64 { 500 }
+fn default_max_delay_ms() -> u64 { 8_000 }
+fn default_true() -> bool { true }
+fn default_retry_statuses() -> Vec<u16> { vec![429, 500, 502, 503, 504] }
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            base_delay_ms: default_base_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            jitter: true,
+            retry_statuses: default_retry_statuses(),
+            respect_retry_after: true,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Exponential backoff for the given retry number (1-based)
+    fn backoff(&self, retry: u32) -> Duration {
+        let exp = self.base_delay_ms.saturating_mul(1u64 << (retry - 1).min(20));
+        let capped = exp.min(self.max_delay_ms);
+        let ms = if self.jitter && capped > 1 {
+            rand::rng().random_range(capped / 2..=capped)
+        } else {
+            capped
+        };
+        Duration::from_millis(ms)
+    }
+
+    /// Wait before the given retry: the backoff, or longer when the server's
+    /// `Retry-After` asks for it. None means give up (`Retry-After` exceeds `max_delay_ms`).
+    fn retry_delay(&self, retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        let backoff = self.backoff(retry);
+        match retry_after.filter(|_| self.respect_retry_after) {
+            Some(wait) if wait > Duration::from_millis(self.max_delay_ms) => None,
+            Some(wait) => Some(wait.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+}
+
+/// Parses `Retry-After` as delta-seconds or an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+/// Transient transport failures worth retrying: failed connects (refused, DNS
+/// hiccups), timeouts and connections reset or closed before the response.
+/// Anything else (a malformed response, a bad request body) would fail again.
+fn is_retryable_error(e: &reqwest::Error) -> bool {
+    if e.is_connect() || e.is_timeout() {
+        return true;
+    }
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            if matches!(io.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe) {
+                return true;
+            }
+        }
+        if err.downcast_ref::<hyper::Error>().is_some_and(|e| e.is_incomplete_message()) {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Sends the request built by `build` for `model`, retrying per `state.retry`.
+/// Returns the last response (which may be a non-success status) or the last error.
+pub async fn send_with_retry<F>(state: &AppState, model: &str, route: &RouteContext<'_>, build: F) -> Result<Response, UpstreamError>
+where
+    F: Fn(&Upstream) -> RequestBuilder,
+{
+    let policy = &state.retry;
+    let max_attempts = policy.max_attempts.max(1);
+    let client = route.tags.client();
+    let mut tried = Vec::new();
+    // Set once a rejected token was dropped, to resend to the same upstream
+    let mut reauth: Option<usize> = None;
+    let mut attempt = 1;
+    loop {
+        let index = match reauth {
+            Some(index) => index,
+            None => state.upstreams.pick(model, &client, route.headers, &tried),
+        };
+        let upstream = state.upstreams.get(index);
+        tried.push(index);
+        route.tags.set_upstream(&upstream.name);
+        let (authorization, token_type) = upstream.credentials.authorization().await
+            .map_err(|e| UpstreamError::Credentials(format!("{}: {}", upstream.name, e)))?;
+        let sent = Instant::now();
+        let result = build(upstream)
+            .header("Authorization", &authorization)
+            .header("X-Snowflake-Authorization-Token-Type", token_type)
+            .send()
+            .await;
+        state.metrics.observe_upstream_latency(model, sent.elapsed().as_secs_f64());
+        // An expired or revoked token gets one immediate resend with a fresh one,
+        // outside the retry budget
+        if let Ok(resp) = &result {
+            if resp.status() == reqwest::StatusCode::UNAUTHORIZED
+                && reauth.is_none()
+                && upstream.credentials.invalidate(&authorization).await
+            {
+                warn!("Upstream {} rejected the token (HTTP 401), renewing it", upstream.name);
+                reauth = Some(index);
+                continue;
+            }
+        }
+        reauth = None;
+        let failed = match &result {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(_) => true,
+        };
+        state.upstreams.record(index, failed);
+        if attempt >= max_attempts {
+            return result.map_err(UpstreamError::Request);
+        }
+        let delay = match &result {
+            Ok(resp) if policy.retry_statuses.contains(&resp.status().as_u16()) => {
+                match policy.retry_delay(attempt, retry_after(resp.headers())) {
+                    Some(delay) => delay,
+                    None => return result.map_err(UpstreamError::Request),
+                }
+            }
+            Err(e) if is_retryable_error(e) => policy.backoff(attempt),
+            _ => return result.map_err(UpstreamError::Request),
+        };
+        let reason = match &result {
+            Ok(resp) => format!("HTTP {}", resp.status().as_u16()),
+            Err(e) => e.to_string(),
+        };
+        state.metrics.record_retry(model, &match &result {
+            Ok(resp) => resp.status().as_u16().to_string(),
+            Err(_) => "connection".to_string(),
+        });
+        warn!(
+            "Upstream {} {} (attempt {}/{}), retrying in {}ms",
+            upstream.name, reason, attempt, max_attempts, delay.as_millis()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Statuses that mean "this model can't serve the request right now":
+/// not enabled in the region/account (403/404), throttled (429) or down (5xx)
+const FALLBACK_STATUSES: [u16; 7] = [403, 404, 429, 500, 502, 503, 504];
+
+/// Phrases in a 403 body that mean the model, not the caller, is the problem
+const MODEL_UNAVAILABLE: [&str; 4] = ["not allowed", "not enabled", "not available", "unavailable"];
+
+/// True when a 403 body says the model isn't enabled or available in this
+/// region; any other 403 is an auth failure that another model won't fix
+fn model_unavailable(body: &[u8]) -> bool {
+    let body = String::from_utf8_lossy(body).to_lowercase();
+    MODEL_UNAVAILABLE.iter().any(|phrase| body.contains(phrase))
+}
+
+/// Reads the body of `resp`, returning it with an identical response to pass on
+async fn buffer(resp: Response) -> (Response, bytes::Bytes) {
+    let status = resp.status();
+    let headers = resp.headers().clone();
+    let body = resp.bytes().await.unwrap_or_default();
+    let mut rebuilt = axum::http::Response::new(body.clone());
+    *rebuilt.status_mut() = status;
+    *rebuilt.headers_mut() = headers;
+    (Response::from(rebuilt), body)
+}
+
+/// Sends the request on `model`, then on each configured fallback while the
+/// model is unavailable or throttled. Returns the result and the model that produced it.
+pub async fn send_with_fallback<F>(
+    state: &AppState,
+    model: &str,
+    route: &RouteContext<'_>,
+    build: F,
+) -> (Result<Response, UpstreamError>, String)
+where
+    F: Fn(&str, &Upstream) -> RequestBuilder,
+{
+    let mut candidates: Vec<String> = vec![model.to_string()];
+    if let Some(fallbacks) = state.model_fallbacks.get(model) {
+        candidates.extend(fallbacks.iter().cloned());
+    }
+    for (i, current) in candidates.iter().enumerate() {
+        let result = send_with_retry(state, current, route, |upstream| build(current, upstream)).await;
+        let Some(next) = candidates.get(i + 1) else {
+            return (result, current.clone());
+        };
+        let result = match result {
+            Ok(resp) if resp.status() == reqwest::StatusCode::FORBIDDEN => {
+                let (resp, body) = buffer(resp).await;
+                if !model_unavailable(&body) {
+                    return (Ok(resp), current.clone());
+                }
+                Ok(resp)
+            }
+            other => other,
+        };
+        let reason = match &result {
+            Ok(resp) if FALLBACK_STATUSES.contains(&resp.status().as_u16()) => format!("HTTP {}", resp.status().as_u16()),
+            Err(UpstreamError::Request(e)) if is_retryable_error(e) => e.to_string(),
+            _ => return (result, current.clone()),
+        };
+        warn!("Model {} unavailable ({}), falling back to {}", current, reason, next);
+    }
+    unreachable!("candidate list always contains the requested model")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use crate::metrics::Metrics;
+    use crate::shutdown::Shutdown;
+
+    fn policy(jitter: bool) -> RetryConfig {
+        RetryConfig { jitter, ..RetryConfig::default() }
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_max_delay() {
+        let policy = policy(false);
+        let delays: Vec<u128> = [1, 2, 3, 4, 5, 6, 40].iter().map(|&r| policy.backoff(r).as_millis()).collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 8000, 8000, 8000]);
+        let jittered = RetryConfig { jitter: true, ..policy };
+        for _ in 0..50 {
+            let ms = jittered.backoff(3).as_millis();
+            assert!((1000..=2000).contains(&ms), "{}", ms);
+        }
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let headers = |value: &str| HeaderMap::from_iter([(reqwest::header::RETRY_AFTER, value.parse().unwrap())]);
+        assert_eq!(retry_after(&headers("3")), Some(Duration::from_secs(3)));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(Duration::ZERO));
+        let soon = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(10));
+        let wait = retry_after(&headers(&soon)).unwrap();
+        assert!(wait > Duration::from_secs(8) && wait <= Duration::from_secs(10), "{:?}", wait);
+        assert_eq!(retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")), Some(Duration::ZERO));
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn gives_up_when_retry_after_exceeds_max_delay() {
+        let policy = policy(false);
+        assert_eq!(policy.retry_delay(1, None), Some(Duration::from_millis(500)));
+        assert_eq!(policy.retry_delay(1, Some(Duration::from_secs(2))), Some(Duration::from_secs(2)));
+        // Never shorter than the backoff
+        assert_eq!(policy.retry_delay(3, Some(Duration::from_millis(100))), Some(Duration::from_secs(2)));
+        assert_eq!(policy.retry_delay(1, Some(Duration::from_secs(8))), Some(Duration::from_secs(8)));
+        assert_eq!(policy.retry_delay(1, Some(Duration::from_secs(9))), None);
+        let ignore = RetryConfig { respect_retry_after: false, ..policy };
+        assert_eq!(ignore.retry_delay(1, Some(Duration::from_secs(60))), Some(Duration::from_millis(500)));
+    }
+
+    /// Requests seen by the mock: (model, Authorization header)
+    type Seen = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// Mock Cortex: 429 for `claude-4-sonnet`, 400 for `bad`, 403 for `denied` and
+    /// `regional` (model not allowed), 401 for token `at-1`,
+    /// 200 otherwise; `/oauth/token-request` hands out `at-1`, `at-2`, ...
+    async fn mock_cortex() -> (String, Seen) {
+        async fn chat(State((seen, _)): State<(Seen, Seen)>, headers: axum::http::HeaderMap, Json(body): Json<Value>) -> axum::response::Response {
+            let model = body["model"].as_str().unwrap_or_default().to_string();
+            let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+            seen.lock().unwrap().push((model.clone(), auth.clone()));
+            match (model.as_str(), auth.as_str()) {
+                (_, "Bearer at-1") => StatusCode::UNAUTHORIZED.into_response(),
+                ("claude-4-sonnet", _) => StatusCode::TOO_MANY_REQUESTS.into_response(),
+                ("bad", _) => StatusCode::BAD_REQUEST.into_response(),
+                ("denied", _) => (StatusCode::FORBIDDEN, Json(json!({"message": "Insufficient privileges to use Cortex"}))).into_response(),
+                ("regional", _) => (StatusCode::FORBIDDEN, Json(json!({"message": "Model regional not allowed in this region"}))).into_response(),
+                _ => Json(json!({"choices": [{"message": {"content": "ok"}}]})).into_response(),
+            }
+        }
+        async fn token(State((_, tokens)): State<(Seen, Seen)>) -> Json<Value> {
+            let mut tokens = tokens.lock().unwrap();
+            tokens.push(Default::default());
+            Json(json!({"access_token": format!("at-{}", tokens.len()), "expires_in": 600}))
+        }
+        let seen = Seen::default();
+        let app = Router::new()
+            .route("/api/v2/cortex/v1/chat/completions", post(chat))
+            .route("/oauth/token-request", post(token))
+            .with_state((seen.clone(), Seen::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/api/v2/cortex/v1", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (base_url, seen)
+    }
+
+    fn state(base_url: &str, max_attempts: u32, config: &str) -> Arc<AppState> {
+        let text = format!(
+            "[proxy.retry]\nmax_attempts = {}\nbase_delay_ms = 1\njitter = false\n[snowflake]\nbase_url = \"{}\"\n{}",
+            max_attempts, base_url, config,
+        );
+        let config: crate::Config = toml::from_str(&text).unwrap();
+        crate::build_state(&config, Arc::new(Metrics::new()), crate::reload::channel().0, Shutdown::new(Duration::ZERO)).unwrap()
+    }
+
+    async fn send(state: &AppState, model: &str) -> (u16, String) {
+        let tags = RequestTags::new("test", "/v1/chat/completions", "anonymous", None);
+        let route = RouteContext { headers: &HeaderMap::new(), tags: &tags };
+        let (result, served) = send_with_fallback(state, model, &route, |m, upstream| {
+            state.client.post(format!("{}/chat/completions", upstream.base_url)).json(&json!({"model": m}))
+        }).await;
+        (result.ok().unwrap().status().as_u16(), served)
+    }
+
+    #[tokio::test]
+    async fn retries_then_falls_back() {
+        let (base_url, seen) = mock_cortex().await;
+        let state = state(&base_url, 3, "pat = \"x\"\n[model_fallbacks]\n\"claude-4-sonnet\" = [\"bad\", \"claude-haiku-4-5\"]\n\"bad\" = [\"claude-haiku-4-5\"]\n");
+        assert_eq!(send(&state, "claude-4-sonnet").await, (400, "bad".to_string()));
+        let models: Vec<String> = seen.lock().unwrap().drain(..).map(|(m, _)| m).collect();
+        // Three attempts on the throttled model; 400 is neither retried nor a reason to fall back
+        assert_eq!(models, ["claude-4-sonnet", "claude-4-sonnet", "claude-4-sonnet", "bad"]);
+
+        assert_eq!(send(&state, "bad").await, (400, "bad".to_string()));
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_after_throttling() {
+        let (base_url, seen) = mock_cortex().await;
+        let state = state(&base_url, 3, "pat = \"x\"\n[model_fallbacks]\n\"claude-4-sonnet\" = [\"claude-haiku-4-5\"]\n");
+        assert_eq!(send(&state, "claude-4-sonnet").await, (200, "claude-haiku-4-5".to_string()));
+        assert_eq!(seen.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn falls_back_on_403_only_when_the_model_is_unavailable() {
+        let (base_url, seen) = mock_cortex().await;
+        let state = state(&base_url, 3, "pat = \"x\"\n[model_fallbacks]\n\"regional\" = [\"claude-haiku-4-5\"]\n\"denied\" = [\"claude-haiku-4-5\"]\n");
+        assert_eq!(send(&state, "regional").await, (200, "claude-haiku-4-5".to_string()));
+        seen.lock().unwrap().clear();
+        assert_eq!(send(&state, "denied").await, (403, "denied".to_string()));
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        assert!(model_unavailable(br#"{"message": "Model claude-4-opus is not enabled for this account"}"#));
+        assert!(model_unavailable(b"model unavailable in region AWS_EU_CENTRAL_1"));
+        assert!(!model_unavailable(br#"{"message": "Role has no access to the Cortex endpoint"}"#));
+    }
+
+    /// Upstream that reads each request, writes `reply` and hangs up (or holds
+    /// the connection open when `hold`); returns its base URL and a connection count
+    async fn raw_upstream(reply: &'static [u8], hold: bool) -> (String, Arc<AtomicUsize>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/api/v2/cortex/v1", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let _ = socket.read(&mut [0; 4096]).await;
+                    let _ = socket.write_all(reply).await;
+                    if hold {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                });
+            }
+        });
+        (base_url, connections)
+    }
+
+    #[tokio::test]
+    async fn retries_only_transient_errors() {
+        let tags = RequestTags::new("test", "/v1/chat/completions", "anonymous", None);
+        let route = RouteContext { headers: &HeaderMap::new(), tags: &tags };
+        for (reply, hold, attempts) in [(&b""[..], false, 3), (&b"garbage\r\n\r\n"[..], true, 1)] {
+            let (base_url, connections) = raw_upstream(reply, hold).await;
+            let state = state(&base_url, 3, "pat = \"x\"\n");
+            let result = send_with_retry(&state, "claude-4-sonnet", &route, |upstream| {
+                state.client.post(format!("{}/chat/completions", upstream.base_url)).json(&json!({}))
+            }).await;
+            assert!(result.is_err());
+            // Closed before a response: retried; a malformed response: not
+            assert_eq!(connections.load(Ordering::SeqCst), attempts, "{:?}", String::from_utf8_lossy(reply));
+        }
+    }
+
+    #[tokio::test]
+    async fn resends_once_with_a_fresh_token_after_401() {
+        let (base_url, seen) = mock_cortex().await;
+        // One attempt only: the 401 resend doesn't count against the retry budget
+        let state = state(&base_url, 1, "[snowflake.auth]\ntype = \"oauth\"\nclient_id = \"c\"\nclient_secret = \"s\"\nrefresh_token = \"r\"\n");
+        assert_eq!(send(&state, "claude-haiku-4-5").await, (200, "claude-haiku-4-5".to_string()));
+        let auths: Vec<String> = seen.lock().unwrap().iter().map(|(_, a)| a.clone()).collect();
+        assert_eq!(auths, ["Bearer at-1", "Bearer at-2"]);
+    }
+}
@@ -0,0 +1,436 @@
+//! Per-request usage ledger (append-only JSONL) and credit accounting
+//!
+//! Every proxied request is tracked by a `RequestTags` handle shared between the
+//! middleware, the handler and any response stream. The ledger line is written
+//! when the last handle is dropped, so streamed requests are recorded once the
+//! final usage chunk has been relayed (or the client disconnected). Lines go
+//! over a channel to one writer thread, so request tasks never block on disk.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, LazyLock, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+/// `[usage]` section
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct UsageConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Ledger file (default: `<data dir>/cortex-proxy/usage.jsonl`)
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Cortex model -> credits per million tokens
+    #[serde(default)]
+    pub prices: HashMap<String, Price>,
+}
+
+/// Credits per million tokens; cache prices default to the input price
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+    #[serde(default)]
+    pub cache_read: Option<f64>,
+    #[serde(default)]
+    pub cache_write: Option<f64>,
+}
+
+impl Price {
+    fn credits(&self, entry: &UsageEntry) -> f64 {
+        let per_token = |price: f64, tokens: u64| price * tokens as f64 / 1_000_000.0;
+        per_token(self.input, entry.input_tokens)
+            + per_token(self.output, entry.output_tokens)
+            + per_token(self.cache_read.unwrap_or(self.input), entry.cache_read_tokens)
+            + per_token(self.cache_write.unwrap_or(self.input), entry.cache_write_tokens)
+    }
+}
+
+impl UsageConfig {
+    pub fn ledger_path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(|| {
+            dirs::data_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("cortex-proxy/usage.jsonl")
+        })
+    }
+}
+
+/// One ledger line
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct UsageEntry {
+    /// RFC 3339, UTC
+    pub ts: String,
+    pub request_id: String,
+    pub client: String,
+    /// False when `client` is the self-reported `x-cortex-client` label, i.e. `[auth]` is off
+    #[serde(default)]
+    pub authenticated: bool,
+    pub path: String,
+    pub model: String,
+    /// Snowflake account (`[[upstreams]]` name) that served the request
+    #[serde(default)]
+    pub upstream: String,
+    pub stream: bool,
+    pub status: u16,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_read_tokens: u64,
+    pub cache_write_tokens: u64,
+    /// Token counts are the proxy's estimate: Cortex reported no usage for this stream
+    #[serde(default)]
+    pub estimated: bool,
+    pub latency_ms: u64,
+    /// Estimated credits, when the model has a price
+    pub credits: Option<f64>,
+}
+
+enum Queued {
+    Line(Arc<File>, String),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Appends ledger lines in arrival order; shared by every config generation
+static WRITER: LazyLock<mpsc::Sender<Queued>> = LazyLock::new(|| {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for write in rx {
+            match write {
+                // One write per line keeps appends from other processes from interleaving
+                Queued::Line(file, line) => {
+                    if let Err(e) = (&*file).write_all(line.as_bytes()) {
+                        warn!("Usage ledger write failed: {}", e);
+                    }
+                }
+                Queued::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+    tx
+});
+
+/// Waits until every line queued so far has been written
+pub async fn flush() {
+    let (done, written) = oneshot::channel();
+    if WRITER.send(Queued::Flush(done)).is_ok() {
+        let _ = written.await;
+    }
+}
+
+pub struct UsageLedger {
+    file: Arc<File>,
+    prices: HashMap<String, Price>,
+}
+
+impl UsageLedger {
+    pub fn open(config: &UsageConfig) -> Result<Self, String> {
+        let path = config.ledger_path();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| format!("usage ledger {}: {}", parent.display(), e))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("usage ledger {}: {}", path.display(), e))?;
+        Ok(Self { file: Arc::new(file), prices: config.prices.clone() })
+    }
+
+    fn append(&self, mut entry: UsageEntry) {
+        entry.credits = self.prices.get(&entry.model).map(|p| p.credits(&entry));
+        let mut line = serde_json::to_string(&entry).unwrap_or_default();
+        line.push('\n');
+        let _ = WRITER.send(Queued::Line(self.file.clone(), line));
+    }
+}
+
+struct Tags {
+    entry: UsageEntry,
+    start: Instant,
+    ledger: Option<Arc<UsageLedger>>,
+    /// Set once the middleware has the response status
+    completed: bool,
+}
+
+impl Drop for Tags {
+    fn drop(&mut self) {
+        // Requests that never reached Cortex (health, models, parse errors) have no model
+        if self.entry.model.is_empty() {
+            return;
+        }
+        if let (Some(ledger), true) = (&self.ledger, self.completed) {
+            let mut entry = std::mem::take(&mut self.entry);
+            entry.ts = rfc3339(SystemTime::now());
+            entry.latency_ms = self.start.elapsed().as_millis() as u64;
+            ledger.append(entry);
+        }
+    }
+}
+
+/// Per-request details filled in by the middleware and handlers, read by the
+/// metrics and written to the usage ledger when the request finishes
+#[derive(Clone)]
+pub struct RequestTags(Arc<Mutex<Tags>>);
+
+impl RequestTags {
+    pub fn new(request_id: &str, path: &str, client: &str, ledger: Option<Arc<UsageLedger>>) -> Self {
+        Self(Arc::new(Mutex::new(Tags {
+            entry: UsageEntry {
+                request_id: request_id.to_string(),
+                path: path.to_string(),
+                client: client.to_string(),
+                ..Default::default()
+            },
+            start: Instant::now(),
+            ledger,
+            completed: false,
+        })))
+    }
+
+    pub fn set_model(&self, model: &str) {
+        self.0.lock().unwrap().entry.model = model.to_string();
+    }
+
+    pub fn set_stream(&self, stream: bool) {
+        self.0.lock().unwrap().entry.stream = stream;
+    }
+
+    /// Replaces the self-reported label with an authenticated client name
+    pub fn set_authenticated_client(&self, client: &str) {
+        let entry = &mut self.0.lock().unwrap().entry;
+        entry.client = client.to_string();
+        entry.authenticated = true;
+    }
+
+    pub fn client(&self) -> String {
+        self.0.lock().unwrap().entry.client.clone()
+    }
+
+    pub fn set_upstream(&self, upstream: &str) {
+        self.0.lock().unwrap().entry.upstream = upstream.to_string();
+    }
+
+    /// Records an Anthropic-shaped usage object (see `anthropic_usage`)
+    pub fn set_usage(&self, usage: &Value) {
+        let count = |field: &str| usage[field].as_u64().unwrap_or(0);
+        let entry = &mut self.0.lock().unwrap().entry;
+        entry.input_tokens = count("input_tokens");
+        entry.output_tokens = count("output_tokens");
+        entry.cache_read_tokens = count("cache_read_input_tokens");
+        entry.cache_write_tokens = count("cache_creation_input_tokens");
+    }
+
+    /// Like `set_usage`, for counts the proxy estimated itself
+    pub fn set_estimated_usage(&self, usage: &Value) {
+        self.set_usage(usage);
+        self.0.lock().unwrap().entry.estimated = true;
+    }
+
+    pub fn set_status(&self, status: u16) {
+        let mut tags = self.0.lock().unwrap();
+        tags.entry.status = status;
+        tags.completed = true;
+    }
+
+    /// (model, stream)
+    pub fn labels(&self) -> (String, bool) {
+        let tags = self.0.lock().unwrap();
+        (tags.entry.model.clone(), tags.entry.stream)
+    }
+}
+
+// ============ Reporting ============
+
+/// Totals for one (day, model, client)
+#[derive(Serialize, Default, Clone)]
+pub struct UsageRow {
+    pub day: String,
+    pub model: String,
+    pub client: String,
+    /// Whether `client` is an `[auth]` key name rather than a self-reported label
+    pub authenticated: bool,
+    pub requests: u64,
+    pub errors: u64,
+    /// Requests whose token counts (and credits) are estimates
+    pub estimated: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_read_tokens: u64,
+    pub cache_write_tokens: u64,
+    pub credits: f64,
+}
+
+/// Aggregates the ledger by day, model and client; `since`/`until` are
+/// inclusive `YYYY-MM-DD` bounds
+pub fn summarize(path: &Path, since: Option<&str>, until: Option<&str>) -> Result<Vec<UsageRow>, String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    let mut rows: BTreeMap<(String, String, String, bool), UsageRow> = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        // Skip a torn final line rather than failing the whole report
+        let Ok(entry) = serde_json::from_str::<UsageEntry>(&line) else { continue };
+        let day = entry.ts.get(..10).unwrap_or_default().to_string();
+        if since.is_some_and(|s| day.as_str() < s) || until.is_some_and(|u| day.as_str() > u) {
+            continue;
+        }
+        let key = (day.clone(), entry.model.clone(), entry.client.clone(), entry.authenticated);
+        let row = rows.entry(key).or_insert_with(|| UsageRow {
+            day,
+            model: entry.model.clone(),
+            client: entry.client.clone(),
+            authenticated: entry.authenticated,
+            ..Default::default()
+        });
+        row.requests += 1;
+        if entry.status >= 400 {
+            row.errors += 1;
+        }
+        if entry.estimated {
+            row.estimated += 1;
+        }
+        row.input_tokens += entry.input_tokens;
+        row.output_tokens += entry.output_tokens;
+        row.cache_read_tokens += entry.cache_read_tokens;
+        row.cache_write_tokens += entry.cache_write_tokens;
+        row.credits += entry.credits.unwrap_or(0.0);
+    }
+    Ok(rows.into_values().collect())
+}
+
+/// Plain-text table for `cortex-proxy usage`
+pub fn format_table(rows: &[UsageRow]) -> String {
+    let mut out = format!(
+        "{:<10}  {:<24}  {:<20}  {:<4}  {:>8}  {:>6}  {:>9}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12}\n",
+        "DAY", "MODEL", "CLIENT", "AUTH", "REQUESTS", "ERRORS", "ESTIMATED", "INPUT", "OUTPUT", "CACHE_READ", "CACHE_WRITE", "CREDITS"
+    );
+    for r in rows {
+        out.push_str(&format!(
+            "{:<10}  {:<24}  {:<20}  {:<4}  {:>8}  {:>6}  {:>9}  {:>12}  {:>12}  {:>12}  {:>12}  {:>12.6}\n",
+            r.day, r.model, r.client, if r.authenticated { "yes" } else { "no" }, r.requests, r.errors, r.estimated, r.input_tokens, r.output_tokens,
+            r.cache_read_tokens, r.cache_write_tokens, r.credits
+        ));
+    }
+    out
+}
+
+/// Formats a timestamp as RFC 3339 UTC with millisecond precision
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, since_epoch.subsec_millis()
+    )
+}
+
+/// Days since 1970-01-01 -> (year, month, day), proleptic Gregorian
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_rfc3339_dates() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        // 2024-02-29 (leap day) 13:45:30.250 UTC
+        let t = UNIX_EPOCH + Duration::from_millis(1_709_214_330_250);
+        assert_eq!(rfc3339(t), "2024-02-29T13:45:30.250Z");
+    }
+
+    #[test]
+    fn prices_cache_tokens_at_input_rate_by_default() {
+        let price = Price { input: 2.0, output: 10.0, cache_read: Some(0.5), cache_write: None };
+        let entry = UsageEntry {
+            input_tokens: 1_000_000,
+            output_tokens: 100_000,
+            cache_read_tokens: 1_000_000,
+            cache_write_tokens: 500_000,
+            ..Default::default()
+        };
+        assert!((price.credits(&entry) - (2.0 + 1.0 + 0.5 + 1.0)).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn writes_finished_requests_and_marks_self_reported_clients() {
+        let path = std::env::temp_dir().join(format!("cortex-proxy-ledger-{}.jsonl", std::process::id()));
+        let config = UsageConfig { enabled: true, path: Some(path.clone()), prices: HashMap::new() };
+        let ledger = Arc::new(UsageLedger::open(&config).unwrap());
+        let finish = |tags: RequestTags| {
+            tags.set_model("claude-4-sonnet");
+            tags.set_status(200);
+        };
+        finish(RequestTags::new("a", "/v1/messages", "team-a", Some(ledger.clone())));
+        let tags = RequestTags::new("b", "/v1/messages", "team-a", Some(ledger.clone()));
+        tags.set_authenticated_client("ci");
+        finish(tags);
+        // No model: never reached Cortex
+        RequestTags::new("c", "/health", "team-a", Some(ledger)).set_status(200);
+        flush().await;
+
+        let lines = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let entries: Vec<UsageEntry> = lines.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let written: Vec<_> = entries.iter().map(|e| (e.request_id.as_str(), e.client.as_str(), e.authenticated)).collect();
+        assert_eq!(written, [("a", "team-a", false), ("b", "ci", true)]);
+    }
+
+    #[test]
+    fn summarizes_by_day_model_and_client() {
+        let path = std::env::temp_dir().join(format!("cortex-proxy-usage-{}.jsonl", std::process::id()));
+        let line = |ts: &str, client: &str, status: u16, input: u64, credits: f64, estimated: bool| {
+            serde_json::to_string(&UsageEntry {
+                ts: ts.to_string(),
+                client: client.to_string(),
+                model: "claude-4-sonnet".to_string(),
+                status,
+                input_tokens: input,
+                estimated,
+                credits: Some(credits),
+                ..Default::default()
+            }).unwrap()
+        };
+        let lines = [
+            line("2026-01-01T10:00:00.000Z", "team-a", 200, 10, 0.5, true),
+            line("2026-01-01T11:00:00.000Z", "team-a", 502, 0, 0.0, false),
+            line("2026-01-01T12:00:00.000Z", "team-b", 200, 5, 0.25, false),
+            line("2026-01-02T09:00:00.000Z", "team-a", 200, 7, 0.1, false),
+            "{\"torn".to_string(),
+        ];
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        let rows = summarize(&path, Some("2026-01-01"), Some("2026-01-01")).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].client.as_str(), rows[0].requests, rows[0].errors, rows[0].estimated, rows[0].input_tokens), ("team-a", 2, 1, 1, 10));
+        assert!((rows[0].credits - 0.5).abs() < 1e-9);
+        assert_eq!((rows[1].client.as_str(), rows[1].requests), ("team-b", 1));
+    }
+}
@@ -0,0 +1,300 @@
+//! Config loading with strict validation, used at startup, on reload and by `check`
+//!
+//! Every config struct denies unknown keys, so typos like `timeout_sec` fail
+//! instead of silently falling back to a default. Placeholder values left over
+//! from the example config (`<YOUR_PAT>`, `https://<account>...`) and a
+//! `base_url` that isn't an http(s) URL of the Cortex API root are rejected
+//! too. Errors name the file and, for keys that come from the file, the line.
+
+use regex::Regex;
+use reqwest::Url;
+use std::fmt;
+use std::path::Path;
+use std::sync::LazyLock;
+use toml_edit::{ImDocument, Item, TableLike};
+
+use crate::config_env::{self, SECRET_FIELDS};
+use crate::Config;
+
+/// Path every Cortex `base_url` ends with
+pub const CORTEX_PATH: &str = "/api/v2/cortex/v1";
+
+/// Keys whose values the example config fills with `<...>` placeholders
+const PLACEHOLDER_KEYS: [&str; 6] = ["base_url", "token_endpoint", "account", "user", "client_id", "private_key_path"];
+
+/// `<...>` placeholder left over from the example config
+static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^<>\s][^<>]*>").unwrap());
+
+/// serde's unknown-field message: the field, then the expected fields if any
+static UNKNOWN_FIELD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^unknown field `([^`]*)`(?:, expected (.*)|, there are no fields)").unwrap()
+});
+
+/// A backquoted name in serde's list of expected fields
+static QUOTED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"`([^`]*)`").unwrap());
+
+/// A problem with one config key (`upstreams[1].pat`), or an environment variable
+#[derive(Debug)]
+pub struct KeyError {
+    pub key: String,
+    pub message: String,
+}
+
+impl KeyError {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { key: key.into(), message: message.into() }
+    }
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// Splits `upstreams[1].auth.user` into its segments; stops at parts serde couldn't name (`?`)
+fn segments(path: &str) -> Vec<Segment<'_>> {
+    let mut out = Vec::new();
+    for part in path.split('.') {
+        let (key, indexes) = part.split_once('[').map_or((part, ""), |(k, rest)| (k, rest));
+        if key == "?" {
+            break;
+        }
+        if !key.is_empty() {
+            out.push(Segment::Key(key));
+        }
+        for index in indexes.split('[').filter_map(|i| i.trim_end_matches(']').parse().ok()) {
+            out.push(Segment::Index(index));
+        }
+    }
+    out
+}
+
+/// Byte offset of the deepest part of `path` found in the parsed file
+fn locate(item: &Item, path: &[Segment], found: Option<usize>) -> Option<usize> {
+    let Some((segment, rest)) = path.split_first() else { return found };
+    match (segment, item) {
+        (Segment::Key(key), _) => {
+            let Some(table) = item.as_table_like() else { return found };
+            locate_key(table, key, rest, found)
+        }
+        (Segment::Index(i), Item::ArrayOfTables(tables)) => match tables.get(*i) {
+            Some(table) => match rest.split_first() {
+                Some((Segment::Key(key), rest)) => locate_key(table, key, rest, table.span().map(|s| s.start).or(found)),
+                _ => table.span().map(|s| s.start).or(found),
+            },
+            None => found,
+        },
+        (Segment::Index(i), Item::Value(toml_edit::Value::Array(array))) => match array.get(*i) {
+            Some(value) => locate(&Item::Value(value.clone()), rest, value.span().map(|s| s.start).or(found)),
+            None => found,
+        },
+        _ => found,
+    }
+}
+
+fn locate_key(table: &dyn TableLike, key: &str, rest: &[Segment], found: Option<usize>) -> Option<usize> {
+    match (table.key(key), table.get(key)) {
+        (Some(k), Some(item)) => locate(item, rest, k.span().map(|s| s.start).or(found)),
+        _ => found,
+    }
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// `file:line: key: message` for each error, one per line
+fn report(path: &Path, text: &str, errors: &[KeyError]) -> String {
+    let doc = ImDocument::parse(text).ok();
+    let mut located: Vec<(Option<usize>, &KeyError)> = errors.iter()
+        .map(|e| (doc.as_ref().and_then(|d| locate(d.as_item(), &segments(&e.key), None)), e))
+        .collect();
+    // File order; keys set only by environment overrides go last
+    located.sort_by_key(|(offset, _)| offset.unwrap_or(usize::MAX));
+    located.into_iter().map(|(offset, e)| match offset {
+        Some(offset) => format!("{}:{}: {}", path.display(), line_of(text, offset), e),
+        None => format!("{}: {}", path.display(), e),
+    }).collect::<Vec<_>>().join("\n")
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+/// Placeholders in credential and URL fields, and `base_url`s outside the Cortex API
+fn check_values(value: &toml::Value, path: &str, errors: &mut Vec<KeyError>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, item) in table {
+                let key_path = join(path, key);
+                if let toml::Value::String(s) = item {
+                    if PLACEHOLDER_KEYS.contains(&key.as_str()) || SECRET_FIELDS.contains(&key.as_str()) {
+                        if let Some(m) = PLACEHOLDER.find(s) {
+                            errors.push(KeyError::new(&key_path, format!("replace the placeholder `{}` with your value", m.as_str())));
+                            continue;
+                        }
+                    }
+                    if key == "base_url" {
+                        if let Err(message) = check_base_url(s) {
+                            errors.push(KeyError::new(&key_path, message));
+                        }
+                    }
+                }
+                check_values(item, &key_path, errors);
+            }
+        }
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                check_values(item, &format!("{}[{}]", path, i), errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_local_host(host: &str) -> bool {
+    host == "localhost" || host.trim_matches(['[', ']']).parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Checks a Cortex `base_url`. Errors (not an http(s) URL, not the Cortex API
+/// root) fail loading; the returned warnings are only reported by `check`.
+pub fn check_base_url(base_url: &str) -> Result<Vec<String>, String> {
+    let url = Url::parse(base_url).map_err(|e| format!("'{}' is not a valid URL: {}", base_url, e))?;
+    let host = url.host_str().unwrap_or("");
+    let mut warnings = Vec::new();
+    match url.scheme() {
+        "https" => {}
+        "http" if is_local_host(host) => {}
+        "http" => warnings.push("plain http sends the Snowflake token unencrypted".to_string()),
+        other => return Err(format!("scheme '{}' is not http or https", other)),
+    }
+    if !host.ends_with(".snowflakecomputing.com") && !is_local_host(host) {
+        warnings.push(format!("'{}' is not a snowflakecomputing.com host", host));
+    }
+    let path = url.path().trim_end_matches('/');
+    if path.ends_with(CORTEX_PATH) {
+        return Ok(warnings);
+    }
+    match path.find(CORTEX_PATH) {
+        Some(i) => Err(format!("must end in {}; drop '{}'", CORTEX_PATH, &path[i + CORTEX_PATH.len()..])),
+        None => Err(format!("must end in {}, e.g. https://<account>.snowflakecomputing.com{}", CORTEX_PATH, CORTEX_PATH)),
+    }
+}
+
+/// Removes the key at `path` (as reported for an unknown field) so deserializing can go on
+fn remove(value: &mut toml::Value, path: &str) -> bool {
+    let segments = segments(path);
+    let Some((Segment::Key(last), parents)) = segments.split_last() else { return false };
+    let mut node = value;
+    for segment in parents {
+        let next = match (segment, node) {
+            (Segment::Key(key), toml::Value::Table(table)) => table.get_mut(*key),
+            (Segment::Index(i), toml::Value::Array(items)) => items.get_mut(*i),
+            _ => None,
+        };
+        let Some(next) = next else { return false };
+        node = next;
+    }
+    match node {
+        toml::Value::Table(table) => table.remove(*last).is_some(),
+        _ => false,
+    }
+}
+
+/// Deserializes `value`, collecting every unknown key before giving up
+fn deserialize(mut value: toml::Value, errors: &mut Vec<KeyError>) -> Option<Config> {
+    loop {
+        let err = match serde_path_to_error::deserialize::<_, Config>(value.clone()) {
+            Ok(config) => return Some(config),
+            Err(err) => err,
+        };
+        let parent = err.path().to_string();
+        let parent = if parent == "." { String::new() } else { parent };
+        // Keep toml's message without its trailing "in `key`" line
+        let message = err.into_inner().to_string().lines().next().unwrap_or_default().to_string();
+        let Some(caps) = UNKNOWN_FIELD.captures(&message) else {
+            errors.push(KeyError::new(if parent.is_empty() { "config".to_string() } else { parent }, message));
+            return None;
+        };
+        // The path normally ends with the unknown field, but not inside tagged enums like `auth`
+        let key = match parent.rsplit('.').next() == Some(&caps[1]) {
+            true => parent,
+            false => join(&parent, &caps[1]),
+        };
+        let expected = caps.get(2).map_or("", |m| m.as_str());
+        let suggestion = QUOTED.captures_iter(expected)
+            .map(|c| c.get(1).unwrap().as_str())
+            .map(|candidate| (strsim::levenshtein(&caps[1], candidate), candidate))
+            .filter(|(distance, _)| *distance <= 2)
+            .min();
+        errors.push(KeyError::new(&key, match suggestion {
+            Some((_, candidate)) => format!("unknown key (did you mean `{}`?)", candidate),
+            None => "unknown key".to_string(),
+        }));
+        if !remove(&mut value, &key) {
+            return None;
+        }
+    }
+}
+
+/// Parses, preprocesses (see `config_env`) and validates a config file's text
+pub fn parse_config(path: &Path, text: &str) -> Result<Config, String> {
+    // toml's own errors already carry the line and a snippet
+    let mut value: toml::Value = toml::from_str(text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    config_env::preprocess(&mut value).map_err(|e| report(path, text, &[e]))?;
+    let mut errors = Vec::new();
+    check_values(&value, "", &mut errors);
+    let config = deserialize(value, &mut errors);
+    match config {
+        Some(config) if errors.is_empty() => Ok(config),
+        _ => Err(report(path, text, &errors)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(text: &str) -> String {
+        match parse_config(Path::new("config.toml"), text) {
+            Ok(_) => String::new(),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys_with_lines() {
+        let text = "[proxy]\ntimeout_sec = 10\n\n[snowflake]\nbase_url = \"https://acct.snowflakecomputing.com/api/v2/cortex/v1\"\npat = \"x\"\n\
+                    [[upstreams]]\nname = \"eu\"\nbase_url = \"https://eu.snowflakecomputing.com/api/v2/cortex/v1\"\npat = \"y\"\nwieght = 2\n\
+                    [metrics]\nenable = true\nfoo = 1\n";
+        assert_eq!(errors(text), [
+            "config.toml:2: proxy.timeout_sec: unknown key (did you mean `timeout_secs`?)",
+            "config.toml:11: upstreams[0].wieght: unknown key (did you mean `weight`?)",
+            "config.toml:13: metrics.enable: unknown key (did you mean `enabled`?)",
+            "config.toml:14: metrics.foo: unknown key",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn rejects_placeholders_and_non_cortex_urls() {
+        let text = "[proxy]\n[snowflake]\nbase_url = \"https://<account>.snowflakecomputing.com/api/v2/cortex/v1\"\npat = \"<YOUR_PAT>\"\n\
+                    [[upstreams]]\nname = \"eu\"\nbase_url = \"https://eu.snowflakecomputing.com/api/v2/cortex/v1/chat/completions\"\npat = \"y\"\n\
+                    [[upstreams]]\nname = \"us\"\nbase_url = \"https://us.snowflakecomputing.com\"\npat = \"z\"\n\
+                    [[upstreams]]\nname = \"ap\"\nbase_url = \"ftp://ap.snowflakecomputing.com/api/v2/cortex/v1\"\npat = \"w\"\n";
+        assert_eq!(errors(text), [
+            "config.toml:3: snowflake.base_url: replace the placeholder `<account>` with your value",
+            "config.toml:4: snowflake.pat: replace the placeholder `<YOUR_PAT>` with your value",
+            "config.toml:7: upstreams[0].base_url: must end in /api/v2/cortex/v1; drop '/chat/completions'",
+            "config.toml:11: upstreams[1].base_url: must end in /api/v2/cortex/v1, e.g. https://<account>.snowflakecomputing.com/api/v2/cortex/v1",
+            "config.toml:15: upstreams[2].base_url: scheme 'ftp' is not http or https",
+        ].join("\n"));
+        let valid = "[proxy]\n[snowflake]\nbase_url = \"https://acct.snowflakecomputing.com/api/v2/cortex/v1/\"\npat = \"x\"\n";
+        assert_eq!(errors(valid), "");
+    }
+}
@@ -6,7 +6,7 @@ set "APP=cortex-proxy"
 set "BIN_DIR=%USERPROFILE%\bin"
 set "CONFIG_DIR=%USERPROFILE%\.config\cortex-proxy"
 set "CONFIG_FILE=%CONFIG_DIR%\config.toml"
-set "EXAMPLE_CONFIG=cortex-proxy.example.toml"
+set "EXAMPLE_CONFIG=cortex-proxy-rs\cortex-proxy.example.toml"
 
 where powershell >nul 2>&1
 if errorlevel 1 (
@@ -6,7 +6,7 @@ APP="cortex-proxy"
 INSTALL_DIR="$HOME/.local/bin"
 CONFIG_DIR="$HOME/.config/cortex-proxy"
 CONFIG_FILE="$CONFIG_DIR/config.toml"
-EXAMPLE_CONFIG="cortex-proxy.example.toml"
+EXAMPLE_CONFIG="cortex-proxy-rs/cortex-proxy.example.toml"
 
 require() {
   if ! command -v "$1" >/dev/null 2>&1; then